@@ -0,0 +1,447 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tracing::Metadata;
+
+use crate::message::{MessagePayload, PayloadMessageType, Thread};
+
+/// Maximum number of characters of the original message quoted in a summary.
+const MAX_SUMMARY_MESSAGE_CHARS: usize = 256;
+
+/// Identifies events which are repeats of one another: emitted from the same callsite, with the same
+/// target and level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub(crate) struct Fingerprint(u64);
+
+impl Fingerprint {
+    pub(crate) fn new(metadata: &Metadata<'_>) -> Self {
+        let mut hasher = DefaultHasher::new();
+        metadata.callsite().hash(&mut hasher);
+        metadata.target().hash(&mut hasher);
+        metadata.level().hash(&mut hasher);
+        Self(hasher.finish())
+    }
+
+    /// Identifies events by an arbitrary value, e.g. panics by their location.
+    pub(crate) fn of<T: Hash>(value: &T) -> Self {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        Self(hasher.finish())
+    }
+
+    /// Identifies the same event sent to a route, whose repeats are aggregated separately from those
+    /// sent elsewhere.
+    pub(crate) fn with_route(self, route: &str) -> Self {
+        let mut hasher = DefaultHasher::new();
+        self.0.hash(&mut hasher);
+        route.hash(&mut hasher);
+        Self(hasher.finish())
+    }
+}
+
+/// Deduplicates repeated events within a time window.
+///
+/// The first occurrence of an event is sent as usual, while repeats within the window are only
+/// counted. Once the window elapses, the background worker sends a single summary of the repeats.
+///
+/// When editing in place, the repeats of an event are instead reported by editing the occurrence
+/// count of the original message, once per window. Its window is only forgotten once a whole window
+/// elapses without repeats, after which the next occurrence is sent as a new message.
+#[derive(Debug)]
+pub(crate) struct Aggregator {
+    window: Duration,
+    edit_in_place: bool,
+    windows: Mutex<HashMap<Fingerprint, Window>>,
+    /// Wakes the background worker once a window opens, so that it waits for the window to elapse.
+    opened: Notify,
+}
+
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    repeats: usize,
+    occurrences: usize,
+    last_seen: SystemTime,
+    summary: Summary,
+}
+
+/// Describes the event being repeated, to be included in its summary.
+#[derive(Debug)]
+pub(crate) struct Summary {
+    pub(crate) app_name: String,
+    pub(crate) target: String,
+    pub(crate) message: String,
+    pub(crate) webhook_url: String,
+    pub(crate) thread: Thread,
+}
+
+/// The repeats of an event within an elapsed window.
+#[derive(Debug)]
+pub(crate) struct Repeats {
+    pub(crate) fingerprint: Fingerprint,
+    /// Total number of occurrences since the event was first sent.
+    pub(crate) occurrences: usize,
+    pub(crate) last_seen: SystemTime,
+    /// Message summarizing the repeats, sent unless the original message is edited instead.
+    pub(crate) summary: MessagePayload,
+}
+
+/// Whether an event should be sent, or was aggregated into the summary of an earlier occurrence.
+pub(crate) enum Occurrence {
+    /// The first occurrence within a window, along with the repeats of the previous window if it
+    /// elapsed without having been reported yet.
+    First(Option<Box<Repeats>>),
+    /// A repeat within the window of an earlier occurrence.
+    Repeat,
+}
+
+impl Aggregator {
+    pub(crate) fn new(window: Duration, edit_in_place: bool) -> Self {
+        Self {
+            window,
+            edit_in_place,
+            windows: Mutex::new(HashMap::new()),
+            opened: Notify::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Fingerprint, Window>> {
+        self.windows.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Record an occurrence of an event.
+    pub(crate) fn record(&self, fingerprint: Fingerprint, summary: impl FnOnce() -> Summary) -> Occurrence {
+        let now = Instant::now();
+        let mut windows = self.lock();
+        let previous = match windows.get_mut(&fingerprint) {
+            // When editing in place, the repeats of an elapsed window are reported along with those
+            // seen until the worker closes it.
+            Some(window) if now < window.started + self.window || (self.edit_in_place && window.repeats > 0) => {
+                window.repeats += 1;
+                window.occurrences += 1;
+                window.last_seen = SystemTime::now();
+                return Occurrence::Repeat;
+            }
+            Some(_) => windows
+                .remove(&fingerprint)
+                .and_then(|window| self.repeats(fingerprint, &window)),
+            None => None,
+        };
+        windows.insert(
+            fingerprint,
+            Window {
+                started: now,
+                repeats: 0,
+                occurrences: 1,
+                last_seen: SystemTime::now(),
+                summary: summary(),
+            },
+        );
+        self.opened.notify_one();
+        Occurrence::First(previous.map(Box::new))
+    }
+
+    /// The instant at which the earliest window elapses, if any.
+    pub(crate) fn next_expiry(&self) -> Option<Instant> {
+        self.lock().values().map(|window| window.started + self.window).min()
+    }
+
+    /// Resolve once a window opened since this was last awaited, or immediately if one opened while
+    /// it was not awaited.
+    pub(crate) async fn opened(&self) {
+        self.opened.notified().await
+    }
+
+    /// Close the windows which have elapsed, returning the repeats seen within them.
+    ///
+    /// When editing in place, also forgets the windows which elapsed without repeats, returning the
+    /// events whose original message is no longer edited.
+    pub(crate) fn expire(&self) -> (Vec<Repeats>, Vec<Fingerprint>) {
+        let now = Instant::now();
+        let elapsed = |started| now >= started + self.window;
+        let mut forgotten = Vec::new();
+        if self.edit_in_place {
+            self.lock().retain(|fingerprint, window| {
+                let quiet = window.repeats == 0 && elapsed(window.started);
+                if quiet {
+                    forgotten.push(*fingerprint);
+                }
+                !quiet
+            });
+        }
+        (self.close(elapsed), forgotten)
+    }
+
+    /// Close every window regardless of its age, e.g. when flushing or shutting down, returning the
+    /// repeats seen within them.
+    pub(crate) fn drain(&self) -> Vec<Repeats> {
+        self.close(|_| true)
+    }
+
+    /// Remove the windows of the events posted in a forum post, e.g. once the span it was opened for
+    /// closes, returning the repeats seen within them.
+    pub(crate) fn forget_thread(&self, key: Fingerprint) -> Vec<Repeats> {
+        let mut windows = self.lock();
+        let forgotten: Vec<Fingerprint> = windows
+            .iter()
+            .filter(|(_, window)| matches!(&window.summary.thread, Thread::ForumPost { key: k, .. } if *k == key))
+            .map(|(fingerprint, _)| *fingerprint)
+            .collect();
+        forgotten
+            .into_iter()
+            .filter_map(|fingerprint| {
+                let window = windows.remove(&fingerprint)?;
+                self.repeats(fingerprint, &window)
+            })
+            .collect()
+    }
+
+    /// Close the open windows started at an instant matching the predicate.
+    fn close(&self, elapsed: impl Fn(Instant) -> bool) -> Vec<Repeats> {
+        let now = Instant::now();
+        let mut windows = self.lock();
+        let elapsed: Vec<Fingerprint> = windows
+            .iter()
+            .filter(|(_, window)| elapsed(window.started))
+            .map(|(fingerprint, _)| *fingerprint)
+            .collect();
+        let mut repeats = Vec::new();
+        for fingerprint in elapsed {
+            if self.edit_in_place {
+                // Start the window again, so that later repeats keep editing the original message
+                // until a whole window elapses without repeats.
+                if let Some(window) = windows.get_mut(&fingerprint).filter(|window| window.repeats > 0) {
+                    repeats.extend(self.repeats(fingerprint, &*window));
+                    window.started = now;
+                    window.repeats = 0;
+                }
+            } else if let Some(window) = windows.remove(&fingerprint) {
+                repeats.extend(self.repeats(fingerprint, &window));
+            }
+        }
+        repeats
+    }
+
+    fn repeats(&self, fingerprint: Fingerprint, window: &Window) -> Option<Repeats> {
+        if window.repeats == 0 {
+            return None;
+        }
+        let Summary {
+            app_name,
+            target,
+            message,
+            webhook_url,
+            thread,
+        } = &window.summary;
+        let mut quoted: String = message.chars().take(MAX_SUMMARY_MESSAGE_CHARS).collect();
+        if quoted.len() < message.len() {
+            quoted.push('…');
+        }
+        let text = format!(
+            ":repeat: **{}** `{}`: {}\nRepeated {} time{} in the last {:?}",
+            app_name,
+            target,
+            quoted,
+            window.repeats,
+            if window.repeats == 1 { "" } else { "s" },
+            self.window,
+        );
+        Some(Repeats {
+            fingerprint,
+            occurrences: window.occurrences,
+            last_seen: window.last_seen,
+            summary: MessagePayload::new(PayloadMessageType::TextNoEmbed(text), webhook_url.clone())
+                .with_thread(thread.clone()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{content, WEBHOOK};
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    fn summary() -> Summary {
+        in_thread(Thread::Channel)
+    }
+
+    fn in_thread(thread: Thread) -> Summary {
+        Summary {
+            app_name: "app".to_string(),
+            target: "server".to_string(),
+            message: "boom".to_string(),
+            webhook_url: WEBHOOK.to_string(),
+            thread,
+        }
+    }
+
+    fn record(aggregator: &Aggregator, event: &str) -> Occurrence {
+        aggregator.record(Fingerprint::of(&event), summary)
+    }
+
+    fn text(repeats: &Repeats) -> String {
+        content(&repeats.summary)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn repeats_within_the_window_are_counted() {
+        let aggregator = Aggregator::new(WINDOW, false);
+        assert!(is_first(&record(&aggregator, "boom")));
+        assert!(matches!(record(&aggregator, "boom"), Occurrence::Repeat));
+        assert!(matches!(record(&aggregator, "boom"), Occurrence::Repeat));
+        assert!(is_first(&record(&aggregator, "other")));
+        assert_eq!(aggregator.next_expiry(), Some(Instant::now() + WINDOW));
+
+        tokio::time::advance(WINDOW - Duration::from_millis(1)).await;
+        assert!(aggregator.expire().0.is_empty());
+        tokio::time::advance(Duration::from_millis(1)).await;
+        let (repeats, forgotten) = aggregator.expire();
+        assert_eq!(repeats.len(), 1);
+        assert_eq!(repeats[0].fingerprint, Fingerprint::of(&"boom"));
+        assert_eq!(repeats[0].occurrences, 3);
+        assert_eq!(
+            text(&repeats[0]),
+            ":repeat: **app** `server`: boom\nRepeated 2 times in the last 60s"
+        );
+        assert!(forgotten.is_empty());
+        assert_eq!(aggregator.next_expiry(), None);
+        assert!(is_first(&record(&aggregator, "boom")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unreported_repeats_are_returned_with_the_next_occurrence() {
+        let aggregator = Aggregator::new(WINDOW, false);
+        record(&aggregator, "boom");
+        record(&aggregator, "boom");
+        tokio::time::advance(WINDOW).await;
+        match record(&aggregator, "boom") {
+            Occurrence::First(Some(previous)) => assert_eq!(previous.occurrences, 2),
+            _ => panic!("expected the repeats of the elapsed window"),
+        }
+        assert!(aggregator.drain().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drain_reports_every_window_with_repeats() {
+        let aggregator = Aggregator::new(WINDOW, false);
+        record(&aggregator, "boom");
+        record(&aggregator, "boom");
+        record(&aggregator, "quiet");
+        assert_eq!(aggregator.drain().len(), 1);
+        assert_eq!(aggregator.next_expiry(), None);
+        assert!(aggregator.drain().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drain_only_reports_the_repeats_seen_so_far() {
+        for edit_in_place in [false, true] {
+            let aggregator = Aggregator::new(WINDOW, edit_in_place);
+            record(&aggregator, "boom");
+            for drained in 0..3 {
+                record(&aggregator, "boom");
+                record(&aggregator, "boom");
+                assert_eq!(aggregator.drain().len(), 1);
+                // The repeats were reported, and are not reported again until the event repeats.
+                assert!(aggregator.drain().is_empty(), "drained {} times", drained);
+                tokio::time::advance(Duration::from_millis(1)).await;
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn edited_events_keep_their_window_once_drained() {
+        let aggregator = Aggregator::new(WINDOW, true);
+        record(&aggregator, "boom");
+        record(&aggregator, "boom");
+        assert_eq!(aggregator.drain()[0].occurrences, 2);
+        assert!(matches!(record(&aggregator, "boom"), Occurrence::Repeat));
+        tokio::time::advance(WINDOW).await;
+        assert_eq!(aggregator.expire().0[0].occurrences, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn forgetting_a_thread_returns_the_repeats_of_its_events() {
+        let aggregator = Aggregator::new(WINDOW, true);
+        let key = Fingerprint::of(&("span", 1));
+        let thread = || in_thread(Thread::forum_post(key, "request"));
+        aggregator.record(Fingerprint::of(&"in thread"), thread);
+        aggregator.record(Fingerprint::of(&"in thread"), thread);
+        aggregator.record(Fingerprint::of(&"quiet in thread"), thread);
+        record(&aggregator, "elsewhere");
+        let repeats = aggregator.forget_thread(key);
+        assert_eq!(repeats.len(), 1);
+        assert_eq!(repeats[0].fingerprint, Fingerprint::of(&"in thread"));
+        assert!(is_first(
+            &aggregator.record(Fingerprint::of(&"quiet in thread"), thread)
+        ));
+        assert!(matches!(record(&aggregator, "elsewhere"), Occurrence::Repeat));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn summaries_quote_long_messages() {
+        let aggregator = Aggregator::new(WINDOW, false);
+        let long = || Summary {
+            message: "m".repeat(1000),
+            ..summary()
+        };
+        aggregator.record(Fingerprint::of(&"long"), long);
+        aggregator.record(Fingerprint::of(&"long"), long);
+        let repeats = aggregator.drain();
+        let quoted = format!("{}…\n", "m".repeat(MAX_SUMMARY_MESSAGE_CHARS));
+        assert!(text(&repeats[0]).contains(&quoted));
+    }
+
+    fn is_first(occurrence: &Occurrence) -> bool {
+        matches!(occurrence, Occurrence::First(None))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn edited_events_are_forgotten_after_a_quiet_window() {
+        let aggregator = Aggregator::new(WINDOW, true);
+        assert!(is_first(&record(&aggregator, "boom")));
+        assert!(matches!(record(&aggregator, "boom"), Occurrence::Repeat));
+        tokio::time::advance(WINDOW).await;
+        let (repeats, forgotten) = aggregator.expire();
+        assert_eq!(repeats.len(), 1);
+        assert_eq!(repeats[0].occurrences, 2);
+        assert!(forgotten.is_empty());
+
+        // The window started again, and elapses without repeats.
+        tokio::time::advance(WINDOW).await;
+        let (repeats, forgotten) = aggregator.expire();
+        assert!(repeats.is_empty());
+        assert_eq!(forgotten, vec![Fingerprint::of(&"boom")]);
+        assert_eq!(aggregator.next_expiry(), None);
+        assert!(is_first(&record(&aggregator, "boom")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn edited_events_keep_counting_while_they_repeat() {
+        let aggregator = Aggregator::new(WINDOW, true);
+        record(&aggregator, "boom");
+        for occurrences in 2..5 {
+            tokio::time::advance(WINDOW / 2).await;
+            assert!(matches!(record(&aggregator, "boom"), Occurrence::Repeat));
+            tokio::time::advance(WINDOW / 2).await;
+            let (repeats, forgotten) = aggregator.expire();
+            assert_eq!(repeats[0].occurrences, occurrences);
+            assert!(forgotten.is_empty());
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quiet_windows_elapsed_before_expiring_are_sent_again() {
+        let aggregator = Aggregator::new(WINDOW, true);
+        record(&aggregator, "boom");
+        tokio::time::advance(WINDOW).await;
+        assert!(is_first(&record(&aggregator, "boom")));
+    }
+}
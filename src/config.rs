@@ -1,11 +1,70 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+use tracing::Level;
+
+use crate::aggregate::Fingerprint;
+use crate::message::Thread;
+
+/// Name of the event field selecting a named webhook to send the event to, e.g.
+/// `error!(discord.channel = "ops", "...")`.
+pub(crate) const CHANNEL_FIELD: &str = "discord.channel";
+
 /// Configuration describing how to forward tracing events to Discord.
+///
+/// Events are sent to the default webhook, unless routed to a named webhook:
+/// 1. by their `discord.channel` field, naming the webhook,
+/// 2. or by the first routing rule matching their level or target.
+///
+/// Names which do not match any named webhook are ignored.
 pub struct DiscordConfig {
     pub(crate) webhook_url: String,
+    /// Where messages are posted in the channel of the default webhook.
+    thread: ThreadMode,
+    /// Webhook URLs by name.
+    webhooks: HashMap<String, String>,
+    /// Rules selecting the named webhook of an event, in order of precedence.
+    routes: Vec<(Route, String)>,
+}
+
+/// How the messages sent to a webhook are organized in threads.
+#[derive(Debug, Clone, Default)]
+pub(crate) enum ThreadMode {
+    /// Post directly in the channel.
+    #[default]
+    Channel,
+    /// Post in an existing thread, by ID.
+    Existing(String),
+    /// Create a forum post for each kind of event, and post its later occurrences into it.
+    ForumPostPerEvent,
+}
+
+impl ThreadMode {
+    /// Where to post an event with the given fingerprint, naming a new forum post after it.
+    pub(crate) fn thread(&self, fingerprint: Fingerprint, name: impl FnOnce() -> String) -> Thread {
+        match self {
+            ThreadMode::Channel => Thread::Channel,
+            ThreadMode::Existing(id) => Thread::Existing(id.clone()),
+            ThreadMode::ForumPostPerEvent => Thread::forum_post(fingerprint, &name()),
+        }
+    }
+}
+
+/// Selects the events routed to a named webhook.
+#[derive(Debug, Clone)]
+enum Route {
+    Level(Level),
+    Target(Regex),
 }
 
 impl DiscordConfig {
     pub fn new(webhook_url: String) -> Self {
-        Self { webhook_url }
+        Self {
+            webhook_url,
+            thread: ThreadMode::Channel,
+            webhooks: HashMap::new(),
+            routes: Vec::new(),
+        }
     }
 
     /// Create a new config for forwarding messages to Discord using configuration
@@ -16,6 +75,60 @@ impl DiscordConfig {
     pub fn new_from_env() -> Self {
         Self::new(std::env::var("DISCORD_WEBHOOK_URL").expect("discord webhook url in env"))
     }
+
+    /// Post the messages of the default webhook in an existing thread of its channel.
+    pub fn thread_id(mut self, thread_id: impl Into<String>) -> Self {
+        self.thread = ThreadMode::Existing(thread_id.into());
+        self
+    }
+
+    /// Create a forum post for each kind of event sent to the default webhook, whose channel must be
+    /// a forum, and post the later occurrences of the event into it.
+    ///
+    /// Events are of the same kind when emitted from the same callsite, with the same target and
+    /// level. The post is named after the first occurrence of the event.
+    pub fn forum_post_per_event(mut self) -> Self {
+        self.thread = ThreadMode::ForumPostPerEvent;
+        self
+    }
+
+    /// Add a webhook which events can be routed to by name.
+    pub fn webhook(mut self, name: impl Into<String>, webhook_url: impl Into<String>) -> Self {
+        self.webhooks.insert(name.into(), webhook_url.into());
+        self
+    }
+
+    /// Route the events of the given level to the named webhook.
+    pub fn route_level(mut self, level: Level, webhook: impl Into<String>) -> Self {
+        self.routes.push((Route::Level(level), webhook.into()));
+        self
+    }
+
+    /// Route the events whose target matches the given regex, e.g. `^billing::`, to the named
+    /// webhook.
+    pub fn route_target(mut self, target: Regex, webhook: impl Into<String>) -> Self {
+        self.routes.push((Route::Target(target), webhook.into()));
+        self
+    }
+
+    /// The URL of the webhook to send an event to, given its level, target and `discord.channel`
+    /// field, along with how its messages are organized in threads.
+    pub(crate) fn resolve(&self, level: &Level, target: &str, channel: Option<&str>) -> (&str, ThreadMode) {
+        let routed = self.routes.iter().filter_map(|(route, webhook)| {
+            let matches = match route {
+                Route::Level(route_level) => route_level == level,
+                Route::Target(route_target) => route_target.is_match(target),
+            };
+            matches.then_some(webhook.as_str())
+        });
+        channel
+            .into_iter()
+            .chain(routed)
+            .find_map(|name| self.webhooks.get(name))
+            .map_or((self.webhook_url.as_str(), self.thread.clone()), |webhook_url| {
+                (webhook_url.as_str(), ThreadMode::Channel)
+            })
+    }
 }
 
 impl Default for DiscordConfig {
@@ -23,3 +136,59 @@ impl Default for DiscordConfig {
         Self::new_from_env()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULT: &str = "https://discord.com/api/webhooks/1/default";
+    const OPS: &str = "https://discord.com/api/webhooks/2/ops";
+    const BILLING: &str = "https://discord.com/api/webhooks/3/billing";
+
+    fn config() -> DiscordConfig {
+        DiscordConfig::new(DEFAULT.to_string())
+            .thread_id("42")
+            .webhook("ops", OPS)
+            .webhook("billing", BILLING)
+    }
+
+    fn webhook<'a>(config: &'a DiscordConfig, level: Level, target: &str, channel: Option<&str>) -> &'a str {
+        config.resolve(&level, target, channel).0
+    }
+
+    #[test]
+    fn unrouted_events_use_the_default_webhook_and_thread() {
+        let config = config().route_level(Level::ERROR, "ops");
+        let (webhook_url, thread) = config.resolve(&Level::INFO, "server", None);
+        assert_eq!(webhook_url, DEFAULT);
+        assert!(matches!(thread, ThreadMode::Existing(id) if id == "42"));
+    }
+
+    #[test]
+    fn channel_field_takes_precedence_over_rules() {
+        let config = config().route_level(Level::ERROR, "ops");
+        let (webhook_url, thread) = config.resolve(&Level::ERROR, "server", Some("billing"));
+        assert_eq!(webhook_url, BILLING);
+        assert!(matches!(thread, ThreadMode::Channel));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let config = config()
+            .route_target(Regex::new("^billing::").unwrap(), "billing")
+            .route_level(Level::ERROR, "ops");
+        assert_eq!(webhook(&config, Level::ERROR, "billing::invoice", None), BILLING);
+        assert_eq!(webhook(&config, Level::ERROR, "server", None), OPS);
+        assert_eq!(webhook(&config, Level::WARN, "billing::invoice", None), BILLING);
+        assert_eq!(webhook(&config, Level::WARN, "server", None), DEFAULT);
+    }
+
+    #[test]
+    fn unknown_names_are_ignored() {
+        let config = config()
+            .route_level(Level::ERROR, "missing")
+            .route_target(Regex::new("^server").unwrap(), "ops");
+        assert_eq!(webhook(&config, Level::ERROR, "server", Some("missing")), OPS);
+        assert_eq!(webhook(&config, Level::ERROR, "client", Some("missing")), DEFAULT);
+    }
+}
@@ -0,0 +1,174 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::message::{MessagePayload, StoredPayload};
+
+/// An append-only file of JSON lines, holding the payloads which could not be delivered to Discord
+/// after exhausting their retries, to be replayed later.
+///
+/// The spool contains the webhook URL of every payload, which grants access to the webhook.
+#[derive(Debug, Clone)]
+pub(crate) struct DeadLetterSpool {
+    path: PathBuf,
+}
+
+impl DeadLetterSpool {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Append a payload to the spool, creating the file if needed.
+    pub(crate) fn append(&self, payload: &MessagePayload) -> io::Result<()> {
+        let mut line = serde_json::to_vec(&StoredPayload::new(payload)?)?;
+        line.push(b'\n');
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        // A single write, so that a line is never interleaved with another writer's.
+        file.write_all(&line)?;
+        file.sync_data()
+    }
+
+    /// Take every payload out of the spool, leaving it empty.
+    ///
+    /// Lines which cannot be parsed, e.g. truncated by a crash while being written, are skipped.
+    pub(crate) fn take(&self) -> io::Result<Vec<MessagePayload>> {
+        let replaying = self.replaying_paths();
+        // Previous replays were interrupted before removing their file, which is replayed first.
+        let mut taken: Vec<&PathBuf> = replaying.iter().filter(|path| path.exists()).collect();
+        // Move the spool aside first, so that payloads failing while it is replayed are appended to
+        // a new spool rather than lost. The file of an interrupted replay is never overwritten, and
+        // the spool is left for the next replay if there is no room for it.
+        if let Some(free) = replaying.iter().find(|path| !path.exists()) {
+            match fs::rename(&self.path, free) {
+                Ok(()) => taken.push(free),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let mut payloads = Vec::new();
+        for path in &taken {
+            payloads.extend(Self::read(path)?);
+        }
+        // Only removed once every file was read, so that they are replayed again should reading one
+        // of them fail.
+        for path in taken {
+            fs::remove_file(path)?;
+        }
+        Ok(payloads)
+    }
+
+    fn read(path: &Path) -> io::Result<Vec<MessagePayload>> {
+        let mut payloads = Vec::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<StoredPayload>(&line).and_then(StoredPayload::into_payload) {
+                Ok(payload) => payloads.push(payload),
+                Err(e) => tracing::warn!(err = %e, "skipping malformed line of the discord dead-letter spool"),
+            }
+        }
+        Ok(payloads)
+    }
+
+    /// The files the spool is moved to while it is replayed.
+    fn replaying_paths(&self) -> [PathBuf; 2] {
+        [".replaying", ".replaying.2"].map(|suffix| {
+            let mut name = self
+                .path
+                .file_name()
+                .map(|name| name.to_os_string())
+                .unwrap_or_default();
+            name.push(suffix);
+            self.path.with_file_name(name)
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{contents, payload, TempFile};
+
+    fn spool(file: &TempFile) -> DeadLetterSpool {
+        DeadLetterSpool::new(file.path().to_path_buf())
+    }
+
+    #[test]
+    fn take_returns_appended_payloads_and_empties_the_spool() {
+        let file = TempFile::new("dead-letter");
+        let spool = spool(&file);
+        spool.append(&payload("first")).unwrap();
+        spool.append(&payload("second")).unwrap();
+
+        assert_eq!(contents(&spool.take().unwrap()), ["first", "second"]);
+        assert!(!spool.path().exists());
+        assert!(spool.take().unwrap().is_empty());
+    }
+
+    #[test]
+    fn take_without_a_spool_returns_nothing() {
+        let file = TempFile::new("dead-letter");
+        let spool = spool(&file);
+        assert!(spool.take().unwrap().is_empty());
+    }
+
+    #[test]
+    fn take_skips_malformed_lines() {
+        let file = TempFile::new("dead-letter");
+        let spool = spool(&file);
+        spool.append(&payload("first")).unwrap();
+        let mut file = OpenOptions::new().append(true).open(spool.path()).unwrap();
+        file.write_all(b"\n{\"truncated\n").unwrap();
+        spool.append(&payload("second")).unwrap();
+
+        assert_eq!(contents(&spool.take().unwrap()), ["first", "second"]);
+    }
+
+    #[test]
+    fn take_replays_an_interrupted_replay_first() {
+        let file = TempFile::new("dead-letter");
+        let spool = spool(&file);
+        spool.append(&payload("interrupted")).unwrap();
+        let [replaying, _] = spool.replaying_paths();
+        fs::rename(spool.path(), &replaying).unwrap();
+        spool.append(&payload("spooled")).unwrap();
+
+        assert_eq!(contents(&spool.take().unwrap()), ["interrupted", "spooled"]);
+        assert!(spool.replaying_paths().iter().all(|path| !path.exists()));
+    }
+
+    #[test]
+    fn take_keeps_every_file_when_reading_one_fails() {
+        let file = TempFile::new("dead-letter");
+        let spool = spool(&file);
+        let [replaying, _] = spool.replaying_paths();
+        // A directory can be opened, but not read.
+        fs::create_dir(&replaying).unwrap();
+        spool.append(&payload("spooled")).unwrap();
+
+        assert!(spool.take().is_err());
+        fs::remove_dir(&replaying).unwrap();
+        assert_eq!(contents(&spool.take().unwrap()), ["spooled"]);
+    }
+
+    #[test]
+    fn take_leaves_the_spool_when_every_replay_was_interrupted() {
+        let file = TempFile::new("dead-letter");
+        let spool = spool(&file);
+        let [first, second] = spool.replaying_paths();
+        for (path, text) in [(&first, "first"), (&second, "second")] {
+            spool.append(&payload(text)).unwrap();
+            fs::rename(spool.path(), path).unwrap();
+        }
+        spool.append(&payload("spooled")).unwrap();
+
+        assert_eq!(contents(&spool.take().unwrap()), ["first", "second"]);
+        assert_eq!(contents(&spool.take().unwrap()), ["spooled"]);
+    }
+}
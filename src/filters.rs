@@ -110,18 +110,6 @@ impl Filter for Option<Vec<Regex>> {
 pub(crate) enum FilterError {
     PositiveFilterFailed,
     NegativeMatchFailed,
-    IoError(Box<dyn std::error::Error>),
-    SerdeError(serde_json::Error),
-}
-
-impl From<Box<dyn std::error::Error>> for FilterError {
-    fn from(e: Box<dyn std::error::Error>) -> Self {
-        FilterError::IoError(e)
-    }
-}
-
-impl From<serde_json::Error> for FilterError {
-    fn from(e: serde_json::Error) -> Self {
-        FilterError::SerdeError(e)
-    }
+    /// The configured level filter is not a valid level.
+    InvalidLevelFilter,
 }
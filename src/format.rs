@@ -0,0 +1,871 @@
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tracing::Level;
+
+use crate::message::PayloadMessageType;
+#[cfg(feature = "embed")]
+use crate::message::MAX_TOTAL_EMBED_CHARS;
+use crate::message::{MAX_CONTENT_CHARS, OCCURRENCES_CHARS};
+
+/// An event sent to Discord, as presented to a [`DiscordFormatter`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct EventView<'a> {
+    /// The name of the application, as given to the layer's builder.
+    pub app_name: &'a str,
+    pub level: Level,
+    pub message: &'a str,
+    pub target: &'a str,
+    /// The names of the spans in scope of the event, from the root span down.
+    pub spans: Vec<&'a str>,
+    /// The fields of the event and of its spans, other than its message.
+    pub fields: Map<String, Value>,
+    pub source_file: &'a str,
+    pub source_line: u32,
+    /// When the event was emitted.
+    pub timestamp: SystemTime,
+    /// The color of the event's level, unless overridden by its `discord.color` field.
+    pub color: u32,
+    /// The emoji of the event's level.
+    pub emoji: &'a str,
+    /// The author to show at the top of embeds, if any.
+    pub author: Option<&'a EmbedAuthor>,
+    /// The URL of the image to show in the corner of embeds, if any.
+    pub thumbnail_url: Option<&'a str>,
+    /// The URL of the icon to show next to the footer of embeds, if any.
+    pub footer_icon_url: Option<&'a str>,
+}
+
+/// The author shown at the top of embeds, e.g. the service emitting the events.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EmbedAuthor {
+    pub name: String,
+    /// The URL the name of the author links to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// The URL of the icon shown next to the name of the author.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon_url: Option<String>,
+}
+
+impl EmbedAuthor {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            url: None,
+            icon_url: None,
+        }
+    }
+
+    /// Link the name of the author to the given URL.
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.url = Some(url.into());
+        self
+    }
+
+    /// Show the icon at the given URL next to the name of the author.
+    pub fn icon_url(mut self, icon_url: impl Into<String>) -> Self {
+        self.icon_url = Some(icon_url.into());
+        self
+    }
+}
+
+/// The decorations of embeds other than their content, shared by every event.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EmbedDecorations {
+    pub(crate) author: Option<EmbedAuthor>,
+    pub(crate) thumbnail_url: Option<String>,
+    pub(crate) footer_icon_url: Option<String>,
+}
+
+impl EventView<'_> {
+    /// Add the author, thumbnail and footer icon of the event to an embed, if any.
+    pub(crate) fn decorate(&self, embed: &mut Map<String, Value>) {
+        if let Some(author) = self.author {
+            embed.insert("author".to_string(), serde_json::json!(author));
+        }
+        if let Some(url) = self.thumbnail_url {
+            embed.insert("thumbnail".to_string(), serde_json::json!({ "url": url }));
+        }
+        if let (Some(url), Some(Value::Object(footer))) = (self.footer_icon_url, embed.get_mut("footer")) {
+            footer.insert("icon_url".to_string(), url.into());
+        }
+    }
+}
+
+impl EventView<'_> {
+    /// The names of the spans in scope of the event, from the root span down, e.g.
+    /// `root > request > db_query`.
+    pub fn span_chain(&self) -> String {
+        self.spans.join(" > ")
+    }
+
+    /// The fields of the event and of its spans, serialized as pretty-printed JSON.
+    pub fn fields_json(&self) -> String {
+        serde_json::to_string_pretty(&self.fields).unwrap_or_default()
+    }
+}
+
+/// Formats events into the messages sent to Discord.
+///
+/// The layer and each of its routes use a formatter, which defaults to [`EmbedFormatter`] when the
+/// `embed` feature is enabled, and to [`TextFormatter`] otherwise.
+pub trait DiscordFormatter: Send + Sync {
+    /// Format an event into the content and embeds of a message.
+    fn format(&self, event: &EventView<'_>) -> PayloadMessageType;
+}
+
+/// Formats events as a rich embed, with the event's fields, target and source laid out in embed
+/// fields.
+///
+/// By default, the fields of the event and of its spans are shown as a single JSON block, split
+/// across as many embed fields as needed. With [`inline_fields`](Self::inline_fields) or
+/// [`promoted_fields`](Self::promoted_fields), fields are shown as native inline embed fields
+/// instead, e.g. `user_id: 42`.
+///
+/// ```
+/// use tracing_layer_discord::EmbedFormatter;
+///
+/// // Show `request_id` and `user_id` first, as inline fields, and the other fields as JSON.
+/// let formatter = EmbedFormatter::new().promoted_fields(["request_id", "user_id"]);
+/// ```
+#[cfg(feature = "embed")]
+#[derive(Debug, Clone, Default)]
+pub struct EmbedFormatter {
+    /// Whether fields are shown as inline embed fields rather than as JSON.
+    inline_fields: bool,
+    /// The keys of the fields shown as inline embed fields, in order, or `None` for every field.
+    promoted: Option<Vec<String>>,
+}
+
+#[cfg(feature = "embed")]
+impl EmbedFormatter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Show each field of the event and of its spans as its own inline embed field, ordered by key.
+    pub fn inline_fields(mut self) -> Self {
+        self.inline_fields = true;
+        self
+    }
+
+    /// Show only the fields with the given keys as inline embed fields, in the given order, and the
+    /// other fields as JSON.
+    pub fn promoted_fields<I, K>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        self.inline_fields = true;
+        self.promoted = Some(keys.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Split the fields into those shown as inline embed fields, in order, and the others.
+    fn promote<'v>(&self, fields: &'v Map<String, Value>) -> (Vec<(&'v str, &'v Value)>, Map<String, Value>) {
+        if !self.inline_fields {
+            return (Vec::new(), fields.clone());
+        }
+        match &self.promoted {
+            None => (
+                fields.iter().map(|(key, value)| (key.as_str(), value)).collect(),
+                Map::new(),
+            ),
+            Some(keys) => {
+                let promoted = keys
+                    .iter()
+                    .filter_map(|key| fields.get_key_value(key.as_str()))
+                    .map(|(key, value)| (key.as_str(), value))
+                    .collect();
+                let rest = fields
+                    .iter()
+                    .filter(|(key, _)| !keys.contains(key))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect();
+                (promoted, rest)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "embed")]
+impl DiscordFormatter for EmbedFormatter {
+    fn format(&self, view: &EventView<'_>) -> PayloadMessageType {
+        let EventView {
+            app_name,
+            message,
+            target,
+            source_file,
+            source_line,
+            ..
+        } = *view;
+        let span = view.span_chain();
+        let event_level = view.level;
+        let event_level_emoji = view.emoji;
+
+        // Maximum characters allowed for a Discord field value, once wrapped in a code block
+        const MAX_CODE_BLOCK_CHARS: usize = MAX_FIELD_VALUE_CHARS - 15;
+        const MAX_ERROR_MESSAGE_CHARS: usize = 2048 - 15;
+        // Fields left for the event's fields, besides the target, the source and the number of
+        // occurrences added once the event repeats
+        const MAX_EVENT_FIELDS: usize = MAX_FIELDS - 3;
+        let chars = |value: &Value| value.as_str().map_or(0, |s| s.chars().count());
+
+        let message = truncate(message.to_string(), MAX_ERROR_MESSAGE_CHARS);
+
+        let mut fields = vec![
+            serde_json::json!({
+                "name": "Target Span",
+                // Deep span scopes or long targets are cut, keeping the value within a code span
+                "value": format!("`{}`", truncate(format!("{}::{}", target, span), MAX_FIELD_VALUE_CHARS - 2)),
+                "inline": true
+            }),
+            serde_json::json!({
+                "name": "Source",
+                "value": format!("`{}#L{}`", source_file, source_line),
+                "inline": true
+            }),
+        ];
+
+        let title = format!("{} - {} {}", app_name, event_level_emoji, event_level);
+        let description = format!("```rust\n{}\n```", message);
+        let field_chars = |field: &Value| chars(&field["name"]) + chars(&field["value"]);
+        // Characters left for the event's fields within Discord's limit on the total size of an
+        // embed, keeping room for the number of occurrences
+        let mut budget = (MAX_TOTAL_EMBED_CHARS - OCCURRENCES_CHARS).saturating_sub(
+            title.chars().count()
+                + description.chars().count()
+                + app_name.chars().count()
+                + view.author.map_or(0, |author| author.name.chars().count())
+                + fields.iter().map(field_chars).sum::<usize>(),
+        );
+
+        let (promoted, mut rest) = self.promote(&view.fields);
+        let mut inline = Vec::new();
+        for (key, value) in promoted {
+            let text = match field_value(value) {
+                text if text.is_empty() => "\"\"".to_string(),
+                text => text,
+            };
+            let field = serde_json::json!({
+                "name": truncate(key.to_string(), MAX_FIELD_NAME_CHARS),
+                "value": truncate(text, MAX_FIELD_VALUE_CHARS),
+                "inline": true
+            });
+            if inline.len() < MAX_EVENT_FIELDS && field_chars(&field) <= budget {
+                budget -= field_chars(&field);
+                inline.push((key, value, field));
+            } else {
+                rest.insert(key.to_string(), value.clone());
+            }
+        }
+        if !rest.is_empty() && inline.len() == MAX_EVENT_FIELDS {
+            // Keep a field for the JSON of the fields which do not fit
+            if let Some((key, value, field)) = inline.pop() {
+                budget += field_chars(&field);
+                rest.insert(key.to_string(), value.clone());
+            }
+        }
+        let slots = MAX_EVENT_FIELDS - inline.len();
+        fields.extend(inline.into_iter().map(|(_, _, field)| field));
+
+        if !self.inline_fields || !rest.is_empty() {
+            // Each chunk is named e.g. `Metadata (12)` and wrapped in a code block
+            const CHUNK_OVERHEAD_CHARS: usize = 13 + 12;
+            let metadata = serde_json::to_string_pretty(&rest).unwrap_or_default();
+            let (max_chars, max_chunks) = if budget >= MAX_CODE_BLOCK_CHARS + CHUNK_OVERHEAD_CHARS {
+                (
+                    MAX_CODE_BLOCK_CHARS,
+                    slots.min(budget / (MAX_CODE_BLOCK_CHARS + CHUNK_OVERHEAD_CHARS)),
+                )
+            } else {
+                (budget.saturating_sub(CHUNK_OVERHEAD_CHARS), 1)
+            };
+            let chunks = if max_chars > 2 {
+                split_lines(&metadata, max_chars, max_chunks)
+            } else {
+                Vec::new()
+            };
+            let numbered = chunks.len() > 1;
+            for (index, chunk) in chunks.into_iter().enumerate() {
+                let name = if numbered {
+                    format!("Metadata ({})", index + 1)
+                } else {
+                    "Metadata".to_string()
+                };
+                fields.push(serde_json::json!({
+                    "name": name,
+                    "value": format!("```json\n{}\n```", chunk),
+                    "inline": false
+                }));
+            }
+        }
+
+        let mut discord_embed = serde_json::json!({
+            "title": title,
+            "description": description,
+            "fields": fields,
+            "footer": {
+                "text": app_name
+            },
+            "color": view.color,
+            "timestamp": iso8601(view.timestamp),
+        });
+        if let Value::Object(embed) = &mut discord_embed {
+            view.decorate(embed);
+        }
+
+        PayloadMessageType::EmbedNoText(vec![discord_embed])
+    }
+}
+
+/// Split text into at most `max_chunks` chunks of at most `max_chars` characters, between lines
+/// unless a single line is too long, ending the last chunk with an ellipsis if the text was cut.
+#[cfg(feature = "embed")]
+fn split_lines(text: &str, max_chars: usize, max_chunks: usize) -> Vec<String> {
+    let mut chunks: Vec<String> = Vec::new();
+    let mut current = String::new();
+    let mut current_chars = 0;
+    for line in text.lines() {
+        let line_chars = line.chars().count();
+        if current_chars > 0 {
+            // Start a new chunk with the line if it fits in one, or else with the rest of the line
+            if current_chars + 1 + line_chars > max_chars && (line_chars <= max_chars || current_chars + 1 >= max_chars)
+            {
+                chunks.push(std::mem::take(&mut current));
+                current_chars = 0;
+            } else {
+                current.push('\n');
+                current_chars += 1;
+            }
+        }
+        for c in line.chars() {
+            if current_chars == max_chars {
+                chunks.push(std::mem::take(&mut current));
+                current_chars = 0;
+            }
+            current.push(c);
+            current_chars += 1;
+        }
+    }
+    if current_chars > 0 || chunks.is_empty() {
+        chunks.push(current);
+    }
+    if chunks.len() > max_chunks {
+        chunks.truncate(max_chunks.max(1));
+        if let Some(last) = chunks.last_mut() {
+            *last = last.chars().take(max_chars - 2).collect::<String>() + "\n…";
+        }
+    }
+    chunks
+}
+
+/// Formats events as a compact markdown text message.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TextFormatter {
+    time_display: TimeDisplay,
+}
+
+/// How the time of an event is displayed in text messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimeDisplay {
+    /// As an ISO-8601 timestamp in UTC, e.g. `2023-07-20T14:03:09.512Z`.
+    #[default]
+    Utc,
+    /// In the local time of each reader, as rendered by Discord.
+    Local,
+}
+
+impl TextFormatter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Configure how the time of events is displayed.
+    pub fn time_display(mut self, time_display: TimeDisplay) -> Self {
+        self.time_display = time_display;
+        self
+    }
+}
+
+impl DiscordFormatter for TextFormatter {
+    fn format(&self, view: &EventView<'_>) -> PayloadMessageType {
+        let EventView {
+            app_name,
+            message,
+            target,
+            source_file,
+            source_line,
+            ..
+        } = *view;
+        let span = view.span_chain();
+        let metadata = view.fields_json();
+        let event_level = view.level.as_str();
+        let time = match self.time_display {
+            TimeDisplay::Utc => iso8601(view.timestamp),
+            TimeDisplay::Local => discord_time(view.timestamp),
+        };
+        let format = |message: &str, metadata: &str| {
+            format!(
+                concat!(
+                    "*Trace from {}*\n",
+                    "*Event [{}]*: \"{}\"\n",
+                    "*Time*: _{}_\n",
+                    "*Target*: _{}_\n",
+                    "*Span*: _{}_\n",
+                    "*Metadata*:\n",
+                    "```",
+                    "{}",
+                    "```\n",
+                    "*Source*: _{}#L{}_",
+                ),
+                app_name, event_level, message, time, target, span, metadata, source_file, source_line,
+            )
+        };
+        // Share the characters left within Discord's limit on the content of a message between the
+        // message and the metadata, keeping room for the number of occurrences. The message gets
+        // what the metadata leaves, and the metadata at least half.
+        let budget = (MAX_CONTENT_CHARS - OCCURRENCES_CHARS).saturating_sub(format("", "").chars().count());
+        let metadata_chars = metadata.chars().count();
+        let message = truncate(message.to_string(), budget - metadata_chars.min(budget / 2));
+        let metadata = truncate(metadata, budget - message.chars().count());
+        let payload = truncate(format(&message, &metadata), MAX_CONTENT_CHARS - OCCURRENCES_CHARS);
+        PayloadMessageType::TextNoEmbed(payload)
+    }
+}
+
+/// Maximum number of characters Discord accepts in the name of an embed field.
+pub(crate) const MAX_FIELD_NAME_CHARS: usize = 256;
+/// Maximum number of characters Discord accepts in the value of an embed field.
+pub(crate) const MAX_FIELD_VALUE_CHARS: usize = 1024;
+/// Maximum number of fields Discord accepts in an embed.
+pub(crate) const MAX_FIELDS: usize = 25;
+
+/// The value of a field as text, without the quotes of JSON strings.
+pub(crate) fn field_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Truncate text to the given number of characters, ending it with an ellipsis if it was cut.
+pub(crate) fn truncate(text: String, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text;
+    }
+    if max_chars == 0 {
+        return String::new();
+    }
+    let mut truncated: String = text.chars().take(max_chars - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+/// Name of the event field overriding the color of its message, e.g.
+/// `info!(discord.color = "#2ecc71", "...")`.
+pub(crate) const COLOR_FIELD: &str = "discord.color";
+
+/// The color and emoji of each level.
+#[derive(Debug, Clone)]
+pub(crate) struct LevelStyles {
+    /// By level, from ERROR to TRACE.
+    colors: [u32; 5],
+    emojis: [String; 5],
+}
+
+impl Default for LevelStyles {
+    fn default() -> Self {
+        Self {
+            colors: [0xff0000, 0xf1c40f, 0x3498db, 0x95a5a6, 0x95a5a6],
+            emojis: [":x:", ":warning:", ":information_source:", ":bug:", ":mag:"].map(String::from),
+        }
+    }
+}
+
+impl LevelStyles {
+    fn index(level: &Level) -> usize {
+        match *level {
+            Level::ERROR => 0,
+            Level::WARN => 1,
+            Level::INFO => 2,
+            Level::DEBUG => 3,
+            Level::TRACE => 4,
+        }
+    }
+
+    pub(crate) fn color(&self, level: &Level) -> u32 {
+        self.colors[Self::index(level)]
+    }
+
+    pub(crate) fn emoji(&self, level: &Level) -> &str {
+        &self.emojis[Self::index(level)]
+    }
+
+    pub(crate) fn set_color(&mut self, level: &Level, color: u32) {
+        self.colors[Self::index(level)] = color;
+    }
+
+    pub(crate) fn set_emoji(&mut self, level: &Level, emoji: String) {
+        self.emojis[Self::index(level)] = emoji;
+    }
+}
+
+/// The largest color Discord accepts, white.
+pub(crate) const MAX_COLOR: u32 = 0xffffff;
+
+/// Parse a color as a decimal number, or a hex code such as `#ff0000` or `0xff0000`, unless it is
+/// larger than Discord accepts.
+pub(crate) fn parse_color(color: &str) -> Option<u32> {
+    let color = color.trim();
+    let color = match color.strip_prefix('#').or_else(|| color.strip_prefix("0x")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => color.parse().ok(),
+    };
+    color.filter(|color| *color <= MAX_COLOR)
+}
+
+/// The color given by the value of a `discord.color` field, as a number or a string, unless it is
+/// larger than Discord accepts.
+pub(crate) fn color_value(value: &Value) -> Option<u32> {
+    match value {
+        Value::Number(number) => number
+            .as_u64()
+            .and_then(|color| u32::try_from(color).ok())
+            .filter(|color| *color <= MAX_COLOR),
+        Value::String(color) => parse_color(color),
+        _ => None,
+    }
+}
+
+/// Format a time as an ISO-8601 timestamp in UTC, e.g. `2023-07-20T14:03:09.512Z`.
+pub(crate) fn iso8601(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (hour, minute, second) = (secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    // Convert the days since the epoch to a civil date, in the proleptic Gregorian calendar.
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Format a time as Discord markup, which Discord renders in the local time of each reader, e.g.
+/// `<t:1689861789:F>`.
+pub(crate) fn discord_time(time: SystemTime) -> String {
+    format!(
+        "<t:{}:F>",
+        time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+    )
+}
+
+/// The formatter used unless another one is configured.
+pub(crate) fn default_formatter() -> Arc<dyn DiscordFormatter> {
+    #[cfg(feature = "embed")]
+    return Arc::new(EmbedFormatter::new());
+    #[cfg(not(feature = "embed"))]
+    return Arc::new(TextFormatter::new());
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+    #[cfg(feature = "embed")]
+    use crate::message::embed_chars;
+
+    fn at(secs: u64, millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_millis(millis)
+    }
+
+    /// An error event with the given message and fields, emitted at `2023-07-20T14:03:09.512Z`.
+    pub(crate) fn event(message: &str, fields: Value) -> EventView<'_> {
+        let fields = match fields {
+            Value::Object(fields) => fields,
+            _ => Map::new(),
+        };
+        EventView {
+            app_name: "app",
+            level: Level::ERROR,
+            message,
+            target: "server::db",
+            spans: vec!["request", "query"],
+            fields,
+            source_file: "src/db.rs",
+            source_line: 42,
+            timestamp: at(1_689_861_789, 512),
+            color: 0xff0000,
+            emoji: ":x:",
+            author: None,
+            thumbnail_url: None,
+            footer_icon_url: None,
+        }
+    }
+
+    /// The first embed of a payload.
+    pub(crate) fn embed(payload: PayloadMessageType) -> Value {
+        match payload {
+            PayloadMessageType::EmbedNoText(mut embeds) | PayloadMessageType::TextWithEmbed(_, mut embeds) => {
+                embeds.remove(0)
+            }
+            other => panic!("expected an embed, got {:?}", other),
+        }
+    }
+
+    fn text(payload: PayloadMessageType) -> String {
+        match payload {
+            PayloadMessageType::TextNoEmbed(text) => text,
+            other => panic!("expected text, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn iso8601_formats_the_epoch() {
+        assert_eq!(iso8601(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    }
+
+    #[test]
+    fn iso8601_keeps_milliseconds() {
+        assert_eq!(iso8601(at(1_689_861_789, 512)), "2023-07-20T14:03:09.512Z");
+        assert_eq!(iso8601(at(946_684_799, 999)), "1999-12-31T23:59:59.999Z");
+    }
+
+    #[test]
+    fn iso8601_handles_leap_years() {
+        assert_eq!(iso8601(at(94_608_000, 0)), "1972-12-31T00:00:00.000Z");
+        assert_eq!(iso8601(at(951_782_400, 0)), "2000-02-29T00:00:00.000Z");
+        assert_eq!(iso8601(at(1_709_164_800, 0)), "2024-02-29T00:00:00.000Z");
+        assert_eq!(iso8601(at(13_574_563_200, 0)), "2400-02-29T00:00:00.000Z");
+    }
+
+    #[test]
+    fn iso8601_skips_february_29_of_century_years() {
+        assert_eq!(iso8601(at(4_107_456_000, 0)), "2100-02-28T00:00:00.000Z");
+        assert_eq!(iso8601(at(4_107_542_400, 0)), "2100-03-01T00:00:00.000Z");
+    }
+
+    #[test]
+    fn iso8601_clamps_times_before_the_epoch() {
+        assert_eq!(iso8601(UNIX_EPOCH - Duration::from_secs(1)), "1970-01-01T00:00:00.000Z");
+    }
+
+    #[test]
+    fn discord_time_is_rendered_in_local_time() {
+        assert_eq!(discord_time(at(1_689_861_789, 512)), "<t:1689861789:F>");
+    }
+
+    #[test]
+    fn text_shows_the_time_of_the_event() {
+        let event = event("boom", json!({}));
+        let utc = text(TextFormatter::new().format(&event));
+        assert!(utc.contains("*Time*: _2023-07-20T14:03:09.512Z_\n"), "{}", utc);
+        let local = text(TextFormatter::new().time_display(TimeDisplay::Local).format(&event));
+        assert!(local.contains("*Time*: _<t:1689861789:F>_\n"), "{}", local);
+    }
+
+    #[cfg(feature = "embed")]
+    fn format_embed(formatter: &EmbedFormatter, event: &EventView<'_>) -> Value {
+        embed(formatter.format(event))
+    }
+
+    #[cfg(feature = "embed")]
+    fn field_names(embed: &Value) -> Vec<&str> {
+        embed["fields"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|field| field["name"].as_str().unwrap())
+            .collect()
+    }
+
+    #[cfg(feature = "embed")]
+    #[test]
+    fn short_text_is_a_single_chunk() {
+        assert_eq!(split_lines("{\n  \"a\": 1\n}", 100, 5), vec!["{\n  \"a\": 1\n}"]);
+        assert_eq!(split_lines("", 100, 5), vec![""]);
+    }
+
+    #[cfg(feature = "embed")]
+    #[test]
+    fn text_is_split_between_lines() {
+        let text = "aaaa\nbbbb\ncccc\ndd";
+        assert_eq!(split_lines(text, 9, 5), vec!["aaaa\nbbbb", "cccc\ndd"]);
+        assert_eq!(split_lines(text, 10, 5), vec!["aaaa\nbbbb", "cccc\ndd"]);
+        assert_eq!(split_lines(text, 4, 5), vec!["aaaa", "bbbb", "cccc", "dd"]);
+    }
+
+    #[cfg(feature = "embed")]
+    #[test]
+    fn long_lines_fill_the_rest_of_a_chunk() {
+        let text = "{\nxxxxxxxxxxxx\n}";
+        assert_eq!(split_lines(text, 5, 10), vec!["{\nxxx", "xxxxx", "xxxx", "}"]);
+    }
+
+    #[cfg(feature = "embed")]
+    #[test]
+    fn chunks_never_exceed_the_limit() {
+        let text = format!(
+            "{{\n  \"a\": \"{}\",\n  \"b\": \"{}\"\n}}",
+            "é".repeat(50),
+            "ü".repeat(7)
+        );
+        for max_chars in 3..60 {
+            let chunks = split_lines(&text, max_chars, usize::MAX);
+            assert!(chunks.iter().all(|chunk| chunk.chars().count() <= max_chars));
+            assert_eq!(chunks.concat().replace('\n', ""), text.replace('\n', ""));
+        }
+    }
+
+    #[cfg(feature = "embed")]
+    #[test]
+    fn extra_chunks_are_cut_with_an_ellipsis() {
+        let chunks = split_lines("aaaa\nbbbb\ncccc", 4, 2);
+        assert_eq!(chunks, vec!["aaaa", "bb\n…"]);
+    }
+
+    #[cfg(feature = "embed")]
+    #[test]
+    fn fields_are_shown_as_json_by_default() {
+        let embed = format_embed(&EmbedFormatter::new(), &event("boom", json!({ "user": "ada" })));
+        assert_eq!(field_names(&embed), vec!["Target Span", "Source", "Metadata"]);
+        assert_eq!(embed["fields"][0]["value"], "`server::db::request > query`");
+        assert_eq!(embed["fields"][2]["value"], "```json\n{\n  \"user\": \"ada\"\n}\n```");
+    }
+
+    #[cfg(feature = "embed")]
+    #[test]
+    fn deep_span_scopes_are_truncated() {
+        let mut event = event("boom", json!({}));
+        event.spans = vec!["a_rather_long_span_name"; 100];
+        let embed = format_embed(&EmbedFormatter::new(), &event);
+        let value = embed["fields"][0]["value"].as_str().unwrap();
+        assert_eq!(value.chars().count(), MAX_FIELD_VALUE_CHARS);
+        assert!(value.starts_with("`server::db::a_rather_long_span_name > "));
+        assert!(value.ends_with("…`"));
+    }
+
+    #[cfg(feature = "embed")]
+    #[test]
+    fn inline_fields_are_shown_as_embed_fields() {
+        let formatter = EmbedFormatter::new().inline_fields();
+        let embed = format_embed(
+            &formatter,
+            &event("boom", json!({ "user": "ada", "attempt": 3, "note": "" })),
+        );
+        assert_eq!(
+            field_names(&embed),
+            vec!["Target Span", "Source", "attempt", "note", "user"]
+        );
+        let fields = &embed["fields"];
+        assert_eq!(fields[2], json!({ "name": "attempt", "value": "3", "inline": true }));
+        assert_eq!(fields[3]["value"], "\"\"");
+        assert_eq!(fields[4]["value"], "ada");
+    }
+
+    #[cfg(feature = "embed")]
+    #[test]
+    fn promoted_fields_are_shown_in_order_and_the_others_as_json() {
+        let formatter = EmbedFormatter::new().promoted_fields(["user", "missing", "attempt"]);
+        let embed = format_embed(
+            &formatter,
+            &event("boom", json!({ "user": "ada", "attempt": 3, "note": "x" })),
+        );
+        assert_eq!(
+            field_names(&embed),
+            vec!["Target Span", "Source", "user", "attempt", "Metadata"]
+        );
+        assert_eq!(embed["fields"][4]["value"], "```json\n{\n  \"note\": \"x\"\n}\n```");
+    }
+
+    #[cfg(feature = "embed")]
+    #[test]
+    fn inline_values_are_truncated() {
+        let value = "v".repeat(2000);
+        let embed = format_embed(
+            &EmbedFormatter::new().inline_fields(),
+            &event("boom", json!({ "value": value })),
+        );
+        let value = embed["fields"][2]["value"].as_str().unwrap();
+        assert_eq!(value.chars().count(), MAX_FIELD_VALUE_CHARS);
+        assert!(value.ends_with('…'));
+    }
+
+    #[cfg(feature = "embed")]
+    #[test]
+    fn fields_that_do_not_fit_are_shown_as_json() {
+        let fields: Map<String, Value> = (0..30).map(|i| (format!("f{:02}", i), json!(i))).collect();
+        let embed = format_embed(
+            &EmbedFormatter::new().inline_fields(),
+            &event("boom", Value::Object(fields)),
+        );
+        let names = field_names(&embed);
+        // One field is kept for the number of occurrences added once the event repeats.
+        assert_eq!(names.len(), MAX_FIELDS - 1);
+        assert_eq!(names[names.len() - 2], "f20");
+        assert_eq!(names[names.len() - 1], "Metadata");
+        let metadata = embed["fields"][names.len() - 1]["value"].as_str().unwrap();
+        assert!(metadata.contains("\"f21\": 21") && metadata.contains("\"f29\": 29"));
+        assert!(!metadata.contains("\"f20\""));
+    }
+
+    #[cfg(feature = "embed")]
+    #[test]
+    fn embeds_stay_within_the_total_size_limit() {
+        let value = "v".repeat(30_000);
+        let message = "m".repeat(3000);
+        let many: Map<String, Value> = (0..20)
+            .map(|i| (format!("f{:02}", i), json!("x".repeat(900))))
+            .collect();
+        let cases = vec![
+            (EmbedFormatter::new(), json!({ "value": value })),
+            (
+                EmbedFormatter::new().inline_fields(),
+                json!({ "value": value, "other": value }),
+            ),
+            (EmbedFormatter::new().inline_fields(), Value::Object(many.clone())),
+            (EmbedFormatter::new().promoted_fields(["f00"]), Value::Object(many)),
+        ];
+        for (formatter, fields) in cases {
+            let embed = format_embed(&formatter, &event(&message, fields));
+            assert!(embed_chars(&embed) <= MAX_TOTAL_EMBED_CHARS - OCCURRENCES_CHARS);
+            assert!(embed["fields"].as_array().unwrap().len() < MAX_FIELDS);
+            for field in embed["fields"].as_array().unwrap() {
+                assert!(field["value"].as_str().unwrap().chars().count() <= MAX_FIELD_VALUE_CHARS);
+            }
+        }
+    }
+
+    #[cfg(feature = "embed")]
+    #[test]
+    fn embeds_carry_the_time_of_the_event() {
+        let embed = embed(EmbedFormatter::new().format(&event("boom", json!({}))));
+        assert_eq!(embed["timestamp"], "2023-07-20T14:03:09.512Z");
+    }
+}
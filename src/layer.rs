@@ -1,16 +1,32 @@
 use regex::Regex;
-use serde::ser::{SerializeMap, Serializer};
-use serde_json::Value;
-use tracing::{Event, Subscriber};
+use serde_json::{Map, Value};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Metadata, Subscriber};
 use tracing_bunyan_formatter::JsonStorage;
 use tracing_subscriber::{layer::Context, Layer};
 
+use crate::aggregate::{Aggregator, Fingerprint, Occurrence, Summary};
+use crate::config::{DiscordConfig, CHANNEL_FIELD};
+use crate::dead_letter::DeadLetterSpool;
 use crate::filters::{EventFilters, Filter, FilterError};
-use crate::message::PayloadMessageType;
-use crate::worker::{BackgroundWorker, WorkerMessage};
-use crate::{config::DiscordConfig, message::MessagePayload, worker::worker, ChannelSender};
-use std::collections::HashMap;
+use crate::format::{
+    color_value, default_formatter, DiscordFormatter, EmbedAuthor, EmbedDecorations, EventView, LevelStyles,
+    COLOR_FIELD, MAX_COLOR,
+};
+use crate::message::{PayloadMessageType, Thread};
+use crate::queue::{OverflowPolicy, SendError};
+use crate::route::Route;
+use crate::span::{SpanFields, SpanThread, SpanTimings};
+use crate::stats::{Counters, FilterStage, WorkerStats};
+use crate::wal::WriteAheadLog;
+use crate::worker::{send_blocking, BackgroundWorker, WorkerConfig, WorkerMessage};
+use crate::{message::MessagePayload, worker::worker, ChannelSender};
+use std::backtrace::Backtrace;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use tracing::log::LevelFilter;
 
 /// Layer for forwarding tracing events to Discord.
@@ -42,18 +58,60 @@ pub struct DiscordLayer {
     /// - Positive: Exclude event fields if the field's key MATCHES any provided regular expressions.
     field_exclusion_filters: Option<Vec<Regex>>,
 
+    /// Filter the spans in scope of an event whose fields are sent along with it, by their name.
+    ///
+    /// Filter type semantics:
+    /// - Positive: Include the fields of a span only if its name MATCHES a given regex.
+    /// - Negative: Exclude the fields of a span if its name MATCHES a given regex.
+    ///
+    /// When unset, the fields of every span in scope are sent.
+    span_field_filters: Option<EventFilters>,
+
     /// Filter events by their level.
     level_filter: Option<String>,
 
-    #[cfg(feature = "embed")]
     app_name: String,
 
     /// Configure the layer's connection to the Discord Webhook API.
     config: DiscordConfig,
 
-    /// An unbounded sender, which the caller must send `WorkerMessage::Shutdown` in order to cancel
-    /// worker's receive-send loop.
+    /// Deduplicates repeated events, if enabled. Shared with the background worker, which sends the
+    /// summaries of repeated events.
+    aggregator: Option<Arc<Aggregator>>,
+
+    /// Whether repeated events are reported by editing the message of their first occurrence.
+    edit_repeated_in_place: bool,
+
+    /// A sender to the worker's queue, which the caller must send `WorkerMessage::Shutdown` in order
+    /// to cancel worker's receive-send loop.
     discord_sender: ChannelSender,
+
+    /// Delivery statistics, shared with the queue and the background worker.
+    counters: Arc<Counters>,
+
+    /// Formats the events sent to the layer's webhook, and to the routes without a formatter.
+    formatter: Arc<dyn DiscordFormatter>,
+
+    /// The color and emoji of each level, given to the formatters.
+    styles: LevelStyles,
+
+    /// The author, thumbnail and footer icon of embeds, given to the formatters.
+    decorations: EmbedDecorations,
+
+    /// Further destinations for events, each with their own filters and format.
+    routes: Vec<Route>,
+
+    /// Filter the spans whose events are grouped into a forum post of their own, by their name.
+    span_threads: Option<EventFilters>,
+
+    /// Filter the spans whose closing is notified, by their name and by their target.
+    span_notifications: Option<(EventFilters, EventFilters)>,
+
+    /// Durations over which spans are alerted on as slow, by the filters matching their name.
+    slow_spans: Vec<(EventFilters, Duration)>,
+
+    /// The key of the next span given a forum post, to tell them apart.
+    next_span_thread: AtomicU64,
 }
 
 impl DiscordLayer {
@@ -61,32 +119,69 @@ impl DiscordLayer {
     /// configuration. This method spawns a task onto the tokio runtime to begin sending tracing
     /// events to Discord.
     ///
-    /// Returns the tracing_subscriber::Layer impl to add to a registry, an unbounded-mpsc sender
-    /// used to shutdown the background worker, and a future to spawn as a task on a tokio runtime
-    /// to initialize the worker's processing and sending of HTTP requests to the Discord API.
-    pub(crate) fn new(
-        app_name: String,
-        target_filters: EventFilters,
-        message_filters: Option<EventFilters>,
-        event_by_field_filters: Option<EventFilters>,
-        field_exclusion_filters: Option<Vec<Regex>>,
-        level_filter: Option<String>,
-        config: DiscordConfig,
-    ) -> (DiscordLayer, BackgroundWorker) {
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    /// Returns the tracing_subscriber::Layer impl to add to a registry, and a handle to the
+    /// background worker, spawned as a task on the tokio runtime, which processes and sends the
+    /// HTTP requests to the Discord API.
+    pub(crate) fn new(builder: DiscordLayerBuilder) -> (DiscordLayer, BackgroundWorker) {
+        let worker_config = builder.worker_config;
+        let dead_letter = worker_config.dead_letter.clone();
+        let counters = Arc::new(Counters::default());
+        let (wal, recovered) = match worker_config.write_ahead_log.clone().map(WriteAheadLog::open) {
+            Some(Ok((wal, recovered))) => (Some(wal), recovered),
+            Some(Err(e)) => {
+                tracing::error!(err = %e, "failed to open discord write-ahead log, queueing in memory only");
+                (None, Vec::new())
+            }
+            None => (None, Vec::new()),
+        };
+        let (tx, rx) = crate::queue::channel(
+            worker_config.capacity,
+            worker_config.overflow_policy,
+            counters.clone(),
+            wal,
+        );
+        // Payloads queued by a previous instance of the process which were never processed.
+        for payload in recovered {
+            counters.record_enqueued(payload.events());
+            let _ = tx.force_send(WorkerMessage::Data(payload));
+        }
+        let edit_repeated_in_place = builder.edit_repeated_in_place;
+        let aggregator = builder
+            .aggregate_window
+            .map(|window| Arc::new(Aggregator::new(window, edit_repeated_in_place)));
         let layer = DiscordLayer {
-            target_filters,
-            message_filters,
-            field_exclusion_filters,
-            event_by_field_filters,
-            level_filter,
-            app_name,
-            config,
+            target_filters: builder.target_filters,
+            message_filters: builder.message_filters,
+            field_exclusion_filters: builder.field_exclusion_filters,
+            span_field_filters: builder.span_field_filters,
+            event_by_field_filters: builder.event_by_field_filters,
+            level_filter: builder.level_filters,
+            app_name: builder.app_name,
+            config: builder.config.unwrap_or_else(DiscordConfig::new_from_env),
+            aggregator: aggregator.clone(),
+            edit_repeated_in_place: edit_repeated_in_place && aggregator.is_some(),
             discord_sender: tx.clone(),
+            counters: counters.clone(),
+            formatter: builder.formatter,
+            styles: builder.styles,
+            decorations: builder.decorations,
+            routes: builder.routes,
+            span_threads: builder.span_threads,
+            span_notifications: builder.span_notifications,
+            slow_spans: builder.slow_spans,
+            next_span_thread: AtomicU64::new(SpanThread::first_key()),
+        };
+        let (deadline_tx, deadline_rx) = tokio::sync::watch::channel(None);
+        let stats = WorkerStats {
+            counters: counters.clone(),
+            queue: tx.depth(),
         };
         let worker = BackgroundWorker {
             sender: tx,
-            handle: tokio::spawn(worker(rx)),
+            join_handle: tokio::spawn(worker(rx, worker_config, aggregator, deadline_rx, counters)),
+            deadline: deadline_tx,
+            stats,
+            dead_letter,
         };
         (layer, worker)
     }
@@ -95,6 +190,75 @@ impl DiscordLayer {
     pub fn builder(app_name: String, target_filters: EventFilters) -> DiscordLayerBuilder {
         DiscordLayerBuilder::new(app_name, target_filters)
     }
+
+    /// Install a panic hook which reports panics to Discord, formatted as an ERROR event.
+    ///
+    /// The report includes the panic message, its location and a backtrace. The panicking thread
+    /// is blocked until the report has been delivered, or until the timeout elapses, so that it is
+    /// sent before the process exits. The previously installed panic hook is invoked afterwards.
+    ///
+    /// The background worker must be able to make progress while the panicking thread is blocked,
+    /// i.e. the tokio runtime must have other worker threads available.
+    pub fn install_panic_hook(&self, timeout: Duration) {
+        let app_name = self.app_name.clone();
+        let formatter = self.formatter.clone();
+        let color = self.styles.color(&Level::ERROR);
+        let emoji = self.styles.emoji(&Level::ERROR).to_string();
+        let decorations = self.decorations.clone();
+        let (webhook_url, thread_mode) = self.config.resolve(&Level::ERROR, "panic", None);
+        let webhook_url = webhook_url.to_string();
+        let sender = self.discord_sender.clone();
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let payload = info.payload();
+            let panic_message = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("Box<dyn Any>");
+            let thread = std::thread::current();
+            let message = format!(
+                "thread '{}' panicked: {}\n\n{}",
+                thread.name().unwrap_or("<unnamed>"),
+                panic_message,
+                Backtrace::force_capture()
+            );
+            let mut fields = Map::new();
+            fields.insert("thread".to_string(), thread.name().into());
+            let view = EventView {
+                app_name: app_name.as_str(),
+                level: Level::ERROR,
+                message: message.as_str(),
+                target: "panic",
+                spans: Vec::new(),
+                fields,
+                source_file: info.location().map_or("Unknown", |location| location.file()),
+                source_line: info.location().map_or(0, |location| location.line()),
+                timestamp: SystemTime::now(),
+                color,
+                emoji: emoji.as_str(),
+                author: decorations.author.as_ref(),
+                thumbnail_url: decorations.thumbnail_url.as_deref(),
+                footer_icon_url: decorations.footer_icon_url.as_deref(),
+            };
+            let fingerprint = Fingerprint::of(&(view.source_file, view.source_line));
+            let thread = thread_mode.thread(fingerprint, || thread_name(&view));
+            let payload = MessagePayload::new(formatter.format(&view), webhook_url.clone()).with_thread(thread);
+            send_blocking(&sender, WorkerMessage::Data(payload), timeout);
+            previous(info);
+        }));
+    }
+}
+
+/// The name of the forum post created for an event: its level, target and the first line of its
+/// message.
+fn thread_name(view: &EventView<'_>) -> String {
+    format!(
+        "{} {}: {}",
+        view.level,
+        view.target,
+        view.message.lines().next().unwrap_or_default()
+    )
 }
 
 /// A builder for creating a Discord layer.
@@ -110,8 +274,19 @@ pub struct DiscordLayerBuilder {
     message_filters: Option<EventFilters>,
     event_by_field_filters: Option<EventFilters>,
     field_exclusion_filters: Option<Vec<Regex>>,
+    span_field_filters: Option<EventFilters>,
     level_filters: Option<String>,
     config: Option<DiscordConfig>,
+    aggregate_window: Option<Duration>,
+    edit_repeated_in_place: bool,
+    worker_config: WorkerConfig,
+    formatter: Arc<dyn DiscordFormatter>,
+    styles: LevelStyles,
+    decorations: EmbedDecorations,
+    routes: Vec<Route>,
+    span_threads: Option<EventFilters>,
+    span_notifications: Option<(EventFilters, EventFilters)>,
+    slow_spans: Vec<(EventFilters, Duration)>,
 }
 
 impl DiscordLayerBuilder {
@@ -122,8 +297,19 @@ impl DiscordLayerBuilder {
             message_filters: None,
             event_by_field_filters: None,
             field_exclusion_filters: None,
+            span_field_filters: None,
             level_filters: None,
             config: None,
+            aggregate_window: None,
+            edit_repeated_in_place: false,
+            worker_config: WorkerConfig::default(),
+            formatter: default_formatter(),
+            styles: LevelStyles::default(),
+            decorations: EmbedDecorations::default(),
+            routes: Vec::new(),
+            span_threads: None,
+            span_notifications: None,
+            slow_spans: Vec::new(),
         }
     }
 
@@ -156,6 +342,19 @@ impl DiscordLayerBuilder {
         self
     }
 
+    /// Select the spans in scope of an event whose fields are sent along with it, by their name.
+    ///
+    /// By default, the fields of every span in scope are sent, the fields of a span overriding the
+    /// fields of the same name of its ancestors.
+    ///
+    /// Filter type semantics:
+    /// - Positive: Include the fields of a span only if its name MATCHES a given regex.
+    /// - Negative: Exclude the fields of a span if its name MATCHES a given regex.
+    pub fn span_field_filters(mut self, filters: EventFilters) -> Self {
+        self.span_field_filters = Some(filters);
+        self
+    }
+
     /// Configure the layer's connection to the Discord Webhook API.
     pub fn discord_config(mut self, config: DiscordConfig) -> Self {
         self.config = Some(config);
@@ -168,17 +367,190 @@ impl DiscordLayerBuilder {
         self
     }
 
+    /// Deduplicate repeated events within a time window.
+    ///
+    /// Events emitted from the same callsite, with the same target and level, are considered
+    /// repeats. Only the first occurrence within the window is sent, followed by a summary of how
+    /// many times it was repeated once the window elapses.
+    pub fn aggregate_window(mut self, window: Duration) -> Self {
+        self.aggregate_window = Some(window);
+        self
+    }
+
+    /// Report repeated events by editing the message of their first occurrence, rather than posting
+    /// a summary of the repeats.
+    ///
+    /// Once per aggregation window, the original message is updated with the number of times the
+    /// event occurred and when it was last seen. Since edits do not notify anyone, the next occurrence
+    /// of an event is posted as a new message once a whole window elapses without repeats. Has no
+    /// effect unless an aggregation window is configured with `aggregate_window`.
+    pub fn edit_repeated_in_place(mut self, enabled: bool) -> Self {
+        self.edit_repeated_in_place = enabled;
+        self
+    }
+
+    /// Format the events sent to Discord with the given formatter, e.g. [`TextFormatter`] for compact
+    /// text messages, rather than the default one.
+    ///
+    /// [`TextFormatter`]: crate::TextFormatter
+    pub fn formatter(mut self, formatter: impl DiscordFormatter + 'static) -> Self {
+        self.formatter = Arc::new(formatter);
+        self
+    }
+
+    /// Set the color of the messages of events of the given level, e.g. `0x3498db`.
+    ///
+    /// Defaults to grey for TRACE and DEBUG, blue for INFO, yellow for WARN and red for ERROR. An
+    /// event can override the color of its message with its `discord.color` field, as a number or a
+    /// hex code such as `"#2ecc71"`.
+    ///
+    /// An event whose `discord.color` field is larger than Discord accepts keeps the color of its
+    /// level.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the color is larger than `0xffffff`, which Discord rejects.
+    pub fn level_color(mut self, level: Level, color: u32) -> Self {
+        assert!(color <= MAX_COLOR, "Discord only accepts colors up to 0xffffff");
+        self.styles.set_color(&level, color);
+        self
+    }
+
+    /// Set the emoji of the messages of events of the given level, e.g. `:rotating_light:`.
+    pub fn level_emoji(mut self, level: Level, emoji: impl Into<String>) -> Self {
+        self.styles.set_emoji(&level, emoji.into());
+        self
+    }
+
+    /// Post messages under the given name, rather than the name of the webhook configured in Discord.
+    pub fn username(mut self, username: impl Into<String>) -> Self {
+        self.worker_config.identity.username = Some(username.into());
+        self
+    }
+
+    /// Post messages with the avatar at the given URL, rather than the avatar of the webhook
+    /// configured in Discord.
+    pub fn avatar_url(mut self, avatar_url: impl Into<String>) -> Self {
+        self.worker_config.identity.avatar_url = Some(avatar_url.into());
+        self
+    }
+
+    /// Show the given author at the top of embeds, e.g. the service emitting the events.
+    pub fn embed_author(mut self, author: EmbedAuthor) -> Self {
+        self.decorations.author = Some(author);
+        self
+    }
+
+    /// Show the image at the given URL in the corner of embeds.
+    pub fn embed_thumbnail(mut self, url: impl Into<String>) -> Self {
+        self.decorations.thumbnail_url = Some(url.into());
+        self
+    }
+
+    /// Show the icon at the given URL next to the footer of embeds.
+    pub fn embed_footer_icon(mut self, url: impl Into<String>) -> Self {
+        self.decorations.footer_icon_url = Some(url.into());
+        self
+    }
+
+    /// Add a named route, sending the events it accepts to its own webhook in its own format.
+    ///
+    /// Routes are evaluated independently of the layer's filters, and share its background worker.
+    pub fn route(mut self, route: Route) -> Self {
+        self.routes.push(route);
+        self
+    }
+
+    /// Group the events of the spans whose name matches the given filters into a forum post of their
+    /// own, which is opened when the span is created and summarized when it closes.
+    ///
+    /// Only the events sent to the default webhook are grouped, whose channel must be a forum. The
+    /// summary reports how long the span was open for, and how many events of each level it had.
+    pub fn span_threads(mut self, filters: EventFilters) -> Self {
+        self.span_threads = Some(filters);
+        self
+    }
+
+    /// Notify when the spans whose name and target match the given filters close, e.g.
+    /// "nightly_import finished in 14m32s with 3 warnings", along with how long they were busy, i.e.
+    /// entered, or idle.
+    ///
+    /// The events emitted within a span are counted whether they are sent to Discord or not. The
+    /// notification is sent like an event of the span's target, at ERROR level if an error was
+    /// emitted within the span, WARN if a warning was, or INFO otherwise.
+    pub fn span_close_notifications(mut self, name_filters: EventFilters, target_filters: EventFilters) -> Self {
+        self.span_notifications = Some((name_filters, target_filters));
+        self
+    }
+
+    /// Alert when a span whose name matches the given filters takes longer than the given threshold,
+    /// e.g. `http_request` spans over 5 seconds, measured from its creation until it closes.
+    ///
+    /// May be called several times to configure thresholds for different spans, the first threshold
+    /// whose filters match a span's name applying to it. The alert is sent like a WARN event of the
+    /// span's target.
+    pub fn slow_span_threshold(mut self, name_filters: EventFilters, threshold: Duration) -> Self {
+        self.slow_spans.push((name_filters, threshold));
+        self
+    }
+
+    /// Bound the number of payloads waiting to be sent to Discord.
+    ///
+    /// By default, the queue is unbounded, and a burst of events while Discord is slow will grow
+    /// memory usage without limit. Once the queue is full, the overflow policy decides which events
+    /// are discarded.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the capacity is zero, as the queue would then discard every event.
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "the capacity of the queue to Discord must be at least 1");
+        self.worker_config.capacity = Some(capacity);
+        self
+    }
+
+    /// Configure what happens to events once the queue has reached its capacity. Defaults to
+    /// `OverflowPolicy::DropNewest`.
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.worker_config.overflow_policy = policy;
+        self
+    }
+
+    /// Batch the payloads of several events into a single request to Discord.
+    ///
+    /// After receiving a payload, the worker waits up to `linger` for further payloads to the same
+    /// webhook, packing up to 10 embeds (and no more than 6000 characters) into a single message.
+    /// Only embed messages are batched.
+    pub fn batch_linger(mut self, linger: Duration) -> Self {
+        self.worker_config.batch_linger = Some(linger);
+        self
+    }
+
+    /// Write the payloads which could not be delivered after exhausting their retries to an
+    /// append-only file of JSON lines, rather than losing them.
+    ///
+    /// Replay them with `BackgroundWorker::replay_dead_letters`, e.g. on the next startup. The file
+    /// contains the webhook URL of every payload, and should be protected accordingly.
+    pub fn dead_letter_spool(mut self, path: impl Into<PathBuf>) -> Self {
+        self.worker_config.dead_letter = Some(DeadLetterSpool::new(path.into()));
+        self
+    }
+
+    /// Back the queue of payloads waiting to be sent to Discord with a write-ahead log, so that the
+    /// payloads queued before the process crashes are sent by the next instance of the process.
+    ///
+    /// Payloads are logged to the file as they are queued, and acknowledged once the background
+    /// worker has processed them. On startup, the payloads left unacknowledged are queued again.
+    /// A payload may be sent twice if the process crashes after delivering it, but before
+    /// acknowledging it. If the file cannot be opened, the queue is kept in memory only.
+    pub fn durable_queue(mut self, path: impl Into<PathBuf>) -> Self {
+        self.worker_config.write_ahead_log = Some(path.into());
+        self
+    }
+
     /// Create a DiscordLayer and its corresponding background worker to (async) send the messages.
     pub fn build(self) -> (DiscordLayer, BackgroundWorker) {
-        DiscordLayer::new(
-            self.app_name,
-            self.target_filters,
-            self.message_filters,
-            self.event_by_field_filters,
-            self.field_exclusion_filters,
-            self.level_filters,
-            self.config.unwrap_or_else(DiscordConfig::new_from_env),
-        )
+        DiscordLayer::new(self)
     }
 }
 
@@ -186,228 +558,372 @@ impl<S> Layer<S> for DiscordLayer
 where
     S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
 {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut visitor = JsonStorage::default();
+        attrs.record(&mut visitor);
+        let name = attrs.metadata().name();
+        let span_thread = self
+            .span_threads
+            .as_ref()
+            .filter(|filters| filters.process(name).is_ok())
+            .map(|_| {
+                let mut fields: Vec<String> = visitor
+                    .values()
+                    .iter()
+                    .map(|(key, value)| match value {
+                        Value::String(s) => format!("{}={}", key, s),
+                        value => format!("{}={}", key, value),
+                    })
+                    .collect();
+                fields.sort();
+                let thread_name = std::iter::once(name.to_string())
+                    .chain(fields)
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let key = self.next_span_thread.fetch_add(1, Ordering::Relaxed);
+                (SpanThread::new(key, &thread_name), thread_name)
+            });
+        let mut extensions = span.extensions_mut();
+        extensions.insert(SpanFields(visitor));
+        let notify = self.notifies_close(attrs.metadata());
+        let slow_after = self.slow_span_threshold(name);
+        if notify || slow_after.is_some() {
+            extensions.insert(SpanTimings::new(notify, slow_after));
+        }
+        if let Some((span_thread, thread_name)) = span_thread {
+            let text = format!(":arrow_forward: **{}** span `{}` started", self.app_name, thread_name);
+            let payload = MessagePayload::new(PayloadMessageType::TextNoEmbed(text), self.config.webhook_url.clone())
+                .with_thread(span_thread.thread.clone());
+            extensions.insert(span_thread);
+            drop(extensions);
+            self.send(WorkerMessage::Data(payload));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(SpanFields(visitor)) = span.extensions_mut().get_mut::<SpanFields>() {
+                values.record(visitor);
+            }
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if !self.times_spans() {
+            return;
+        }
+        if let Some(span) = ctx.span(id) {
+            if let Some(timings) = span.extensions_mut().get_mut::<SpanTimings>() {
+                timings.enter();
+            }
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        if !self.times_spans() {
+            return;
+        }
+        if let Some(span) = ctx.span(id) {
+            if let Some(timings) = span.extensions_mut().get_mut::<SpanTimings>() {
+                timings.exit();
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        if let Some(span_thread) = span.extensions().get::<SpanThread>() {
+            // The events posted in the thread are unique to the span, so their repeats are reported
+            // now rather than kept around to edit messages which will never repeat again.
+            for repeats in self.aggregator.iter().flat_map(|a| a.forget_thread(span_thread.key)) {
+                self.send(WorkerMessage::Repeats(repeats));
+            }
+            let text = span_thread.summary(&self.app_name, span.name());
+            let payload = MessagePayload::new(PayloadMessageType::TextNoEmbed(text), self.config.webhook_url.clone())
+                .with_thread(span_thread.thread.clone());
+            self.send(WorkerMessage::Data(payload));
+            self.send(WorkerMessage::CloseThread(span_thread.key));
+        }
+        let mut extensions = span.extensions_mut();
+        if let Some(timings) = extensions.get_mut::<SpanTimings>() {
+            timings.close();
+            let notification = timings
+                .notify
+                .then(|| (timings.level(), timings.notification(&self.app_name, span.name())));
+            let slow_alert = timings
+                .slow_alert(&self.app_name, span.name())
+                .map(|alert| (Level::WARN, alert));
+            let target = span.metadata().target();
+            for (level, text) in notification.into_iter().chain(slow_alert) {
+                let (webhook_url, thread_mode) = self.config.resolve(&level, target, None);
+                let thread = thread_mode.thread(Fingerprint::new(span.metadata()), || {
+                    format!("{} {}", target, span.name())
+                });
+                let payload = MessagePayload::new(PayloadMessageType::TextNoEmbed(text), webhook_url.to_string())
+                    .with_thread(thread);
+                self.send(WorkerMessage::Data(payload));
+            }
+        }
+    }
+
     fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
-        let current_span = ctx.lookup_current();
+        // Ignore events emitted by this crate, e.g. delivery failures reported by the worker, which
+        // would otherwise be sent back to Discord.
+        if event.metadata().target().starts_with(env!("CARGO_CRATE_NAME")) {
+            return;
+        }
+        // The spans in scope of the event, from the root span down to the current span.
+        let spans: Vec<_> = ctx
+            .lookup_current()
+            .into_iter()
+            .flat_map(|span| span.scope().from_root())
+            .collect();
+        if self.times_spans() {
+            for span in &spans {
+                if let Some(timings) = span.extensions().get::<SpanTimings>() {
+                    timings.events.record(event.metadata().level());
+                }
+            }
+        }
         let mut event_visitor = JsonStorage::default();
         event.record(&mut event_visitor);
 
-        let format = || {
-            const KEYWORDS: [&str; 2] = ["message", "error"];
-
-            let target = event.metadata().target();
-            self.target_filters.process(target)?;
-
-            // Extract the "message" field, if provided. Fallback to the target, if missing.
-            let message = event_visitor
-                .values()
-                .get("message")
-                .and_then(|v| match v {
+        let level = event.metadata().level();
+        let target = event.metadata().target();
+
+        // Extract the "message" field, if provided. Fallback to the target, if missing.
+        let message = event_visitor
+            .values()
+            .get("message")
+            .and_then(|v| match v {
+                Value::String(s) => Some(s.as_str()),
+                _ => None,
+            })
+            .or_else(|| {
+                event_visitor.values().get("error").and_then(|v| match v {
                     Value::String(s) => Some(s.as_str()),
                     _ => None,
                 })
-                .or_else(|| {
-                    event_visitor.values().get("error").and_then(|v| match v {
-                        Value::String(s) => Some(s.as_str()),
-                        _ => None,
-                    })
-                })
-                .unwrap_or("No message");
-
-            self.message_filters.process(message)?;
-            if let Some(level_filters) = &self.level_filter {
-                let message_level = {
-                    LevelFilter::from_str(event.metadata().level().as_str())
-                        .map_err(|e| FilterError::IoError(Box::new(e)))?
-                };
-                let level_threshold =
-                    LevelFilter::from_str(level_filters).map_err(|e| FilterError::IoError(Box::new(e)))?;
-                if message_level > level_threshold {
-                    return Err(FilterError::PositiveFilterFailed);
+            })
+            .unwrap_or("No message");
+
+        // All the other fields associated with the event, except the message we already used.
+        let keys: Vec<&str> = event_visitor
+            .values()
+            .keys()
+            .copied()
+            .filter(|key| self.sends_field(key))
+            .collect();
+
+        let accepted = self.accepts(level, target, message, &keys).is_ok();
+        let routes: Vec<&Route> = self
+            .routes
+            .iter()
+            .filter(|route| route.accepts(level, target, message, &keys))
+            .collect();
+        if !accepted && routes.is_empty() {
+            return;
+        }
+
+        // Add the fields of the spans in scope from the root span down, then those of the event, so
+        // that the fields of a span override those of its ancestors, and the fields of the event
+        // override those of its spans.
+        let mut fields = Map::new();
+        let field_spans = spans
+            .iter()
+            .filter(|span| self.span_field_filters.process(span.name()).is_ok());
+        for span in field_spans {
+            let extensions = span.extensions();
+            if let Some(SpanFields(visitor)) = extensions.get::<SpanFields>() {
+                for (key, value) in visitor.values().iter().filter(|(key, _)| self.sends_field(key)) {
+                    fields.insert(key.to_string(), value.clone());
                 }
             }
+        }
+        for key in &keys {
+            fields.insert(key.to_string(), event_visitor.values()[key].clone());
+        }
 
-            let mut metadata_buffer = Vec::new();
-            let mut serializer = serde_json::Serializer::new(&mut metadata_buffer);
-            let mut map_serializer = serializer.serialize_map(None)?;
-            // Add all the other fields associated with the event, expect the message we
-            // already used.
-            for (key, value) in event_visitor
+        let view = EventView {
+            app_name: self.app_name.as_str(),
+            level: *level,
+            message,
+            target,
+            spans: spans.iter().map(|span| span.name()).collect(),
+            fields,
+            source_file: event.metadata().file().unwrap_or("Unknown"),
+            source_line: event.metadata().line().unwrap_or(0),
+            timestamp: SystemTime::now(),
+            color: event_visitor
                 .values()
+                .get(COLOR_FIELD)
+                .and_then(color_value)
+                .unwrap_or_else(|| self.styles.color(level)),
+            emoji: self.styles.emoji(level),
+            author: self.decorations.author.as_ref(),
+            thumbnail_url: self.decorations.thumbnail_url.as_deref(),
+            footer_icon_url: self.decorations.footer_icon_url.as_deref(),
+        };
+        let fingerprint = Fingerprint::new(event.metadata());
+        if accepted {
+            let channel = event_visitor.values().get(CHANNEL_FIELD).and_then(Value::as_str);
+            let (webhook_url, thread_mode) = self.config.resolve(level, target, channel);
+            // The closest span whose events are grouped into a forum post, if any.
+            let span_thread = spans
                 .iter()
-                .filter(|(&key, _)| !KEYWORDS.contains(&key))
-                .filter(|(&key, _)| self.field_exclusion_filters.process(key).is_ok())
+                .rev()
+                .find(|span| span.extensions().get::<SpanThread>().is_some());
+            let span_extensions = span_thread.as_ref().map(|span| span.extensions());
+            match span_extensions
+                .as_ref()
+                .and_then(|extensions| extensions.get::<SpanThread>())
             {
-                self.event_by_field_filters.process(key)?;
-                map_serializer.serialize_entry(key, value)?;
-            }
-            // Add all the fields from the current span, if we have one.
-            if let Some(span) = &current_span {
-                let extensions = span.extensions();
-                if let Some(visitor) = extensions.get::<JsonStorage>() {
-                    for (key, value) in visitor.values() {
-                        map_serializer.serialize_entry(key, value)?;
-                    }
+                Some(span_thread) if webhook_url == self.config.webhook_url => {
+                    span_thread.events.record(level);
+                    let fingerprint = Fingerprint::of(&(fingerprint, span_thread.key));
+                    let thread = span_thread.thread.clone();
+                    self.dispatch(fingerprint, webhook_url, thread, &*self.formatter, &view);
+                }
+                _ => {
+                    let thread = thread_mode.thread(fingerprint, || thread_name(&view));
+                    self.dispatch(fingerprint, webhook_url, thread, &*self.formatter, &view);
                 }
             }
-            map_serializer.end()?;
+        }
+        for route in routes {
+            let fingerprint = fingerprint.with_route(&route.name);
+            let thread = route.thread.thread(fingerprint, || thread_name(&view));
+            let formatter = route.formatter.as_deref().unwrap_or(&*self.formatter);
+            self.dispatch(fingerprint, &route.webhook_url, thread, formatter, &view);
+        }
+    }
+}
 
-            let span = match &current_span {
-                Some(span) => span.metadata().name(),
-                None => "",
-            };
+impl DiscordLayer {
+    /// Whether spans are timed, to notify when they close or alert on them when slow.
+    fn times_spans(&self) -> bool {
+        self.span_notifications.is_some() || !self.slow_spans.is_empty()
+    }
 
-            let metadata = {
-                let data: HashMap<String, serde_json::Value> =
-                    serde_json::from_slice(metadata_buffer.as_slice()).unwrap();
-                serde_json::to_string_pretty(&data).unwrap()
-            };
+    /// The duration over which a span is alerted on as slow, according to its name.
+    fn slow_span_threshold(&self, name: &str) -> Option<Duration> {
+        self.slow_spans
+            .iter()
+            .find(|(filters, _)| filters.process(name).is_ok())
+            .map(|(_, threshold)| *threshold)
+    }
 
-            Ok(Self::format_payload(
-                self.app_name.as_str(),
-                message,
-                event,
-                target,
-                span,
-                metadata,
-            ))
-        };
+    /// Whether the closing of a span is notified, according to its name and target.
+    fn notifies_close(&self, metadata: &Metadata<'_>) -> bool {
+        self.span_notifications
+            .as_ref()
+            .is_some_and(|(name_filters, target_filters)| {
+                name_filters.process(metadata.name()).is_ok() && target_filters.process(metadata.target()).is_ok()
+            })
+    }
 
-        let result: Result<PayloadMessageType, FilterError> = format();
-        if let Ok(formatted) = result {
-            let payload = MessagePayload::new(formatted, self.config.webhook_url.clone());
-            if let Err(e) = self.discord_sender.send(WorkerMessage::Data(payload)) {
-                tracing::error!(err = %e, "failed to send discord payload to given channel")
-            };
+    /// Whether a field of an event or of its spans is sent along with the event, rather than being
+    /// the message, selecting the event's webhook or color, or excluded.
+    fn sends_field(&self, key: &str) -> bool {
+        const KEYWORDS: [&str; 2] = ["message", "error"];
+        !KEYWORDS.contains(&key)
+            && key != CHANNEL_FIELD
+            && key != COLOR_FIELD
+            && self.field_exclusion_filters.process(key).is_ok()
+    }
+
+    /// Whether an event is sent to the layer's webhook, according to the layer's own filters.
+    fn accepts(&self, level: &Level, target: &str, message: &str, keys: &[&str]) -> Result<(), FilterError> {
+        if let Err(e) = self.target_filters.process(target) {
+            self.counters.record_filtered(FilterStage::Target);
+            return Err(e);
+        }
+        if let Err(e) = self.message_filters.process(message) {
+            self.counters.record_filtered(FilterStage::Message);
+            return Err(e);
+        }
+        if let Some(level_filters) = &self.level_filter {
+            let message_level = LevelFilter::from_str(level.as_str()).map_err(|_| FilterError::InvalidLevelFilter)?;
+            let level_threshold = LevelFilter::from_str(level_filters).map_err(|_| FilterError::InvalidLevelFilter)?;
+            if message_level > level_threshold {
+                self.counters.record_filtered(FilterStage::Level);
+                return Err(FilterError::PositiveFilterFailed);
+            }
+        }
+        for key in keys {
+            if let Err(e) = self.event_by_field_filters.process(key) {
+                self.counters.record_filtered(FilterStage::Fields);
+                return Err(e);
+            }
         }
+        Ok(())
     }
-}
 
-impl DiscordLayer {
-    #[cfg(feature = "embed")]
-    fn format_payload(
-        app_name: &str,
-        message: &str,
-        event: &Event,
-        target: &str,
-        span: &str,
-        metadata: String,
-    ) -> PayloadMessageType {
-        let event_level = event.metadata().level();
-        let event_level_emoji = match *event_level {
-            tracing::Level::TRACE => ":mag:",
-            tracing::Level::DEBUG => ":bug:",
-            tracing::Level::INFO => ":information_source:",
-            tracing::Level::WARN => ":warning:",
-            tracing::Level::ERROR => ":x:",
-        };
-        let source_file = event.metadata().file().unwrap_or("Unknown");
-        let source_line = event.metadata().line().unwrap_or(0);
-
-        // Maximum characters allowed for a Discord field value
-        const MAX_FIELD_VALUE_CHARS: usize = 1024 - 15;
-        const MAX_ERROR_MESSAGE_CHARS: usize = 2048 - 15;
-
-        // Truncate error_message if it exceeds the limit
-        let mut truncated_message = String::new();
-        if message.chars().count() > MAX_ERROR_MESSAGE_CHARS {
-            let mut char_count = 0;
-            for c in message.chars() {
-                char_count += 1;
-                if char_count > MAX_ERROR_MESSAGE_CHARS {
-                    break;
+    /// Send an event to a webhook, unless it was aggregated into the summary of an earlier
+    /// occurrence sent there.
+    fn dispatch(
+        &self,
+        fingerprint: Fingerprint,
+        webhook_url: &str,
+        thread: Thread,
+        formatter: &dyn DiscordFormatter,
+        view: &EventView<'_>,
+    ) {
+        if let Some(aggregator) = &self.aggregator {
+            let summary = || Summary {
+                app_name: self.app_name.clone(),
+                target: view.target.to_string(),
+                message: view.message.to_string(),
+                webhook_url: webhook_url.to_string(),
+                thread: thread.clone(),
+            };
+            match aggregator.record(fingerprint, summary) {
+                Occurrence::First(Some(previous)) => self.send(WorkerMessage::Repeats(*previous)),
+                Occurrence::First(None) => {}
+                Occurrence::Repeat => {
+                    self.counters.record_filtered(FilterStage::Repeated);
+                    return;
                 }
-                truncated_message.push(c);
             }
         }
-        let message = if truncated_message.is_empty() {
-            message
-        } else {
-            truncated_message.as_str()
+        let mut payload = MessagePayload::new(formatter.format(view), webhook_url.to_string()).with_thread(thread);
+        if self.edit_repeated_in_place {
+            payload = payload.with_fingerprint(fingerprint);
+        }
+        self.send(WorkerMessage::Data(payload));
+    }
+}
+
+impl DiscordLayer {
+    /// Queue a message to be processed by the background worker.
+    fn send(&self, message: WorkerMessage) {
+        let events = message.events();
+        match self.discord_sender.send(message) {
+            Ok(()) => self.counters.record_enqueued(events),
+            // The payload was discarded according to the configured overflow policy.
+            Err(SendError::Full) => {}
+            Err(e) => tracing::error!(err = %e, "failed to send discord payload to given channel"),
         };
+    }
+}
 
-        let mut discord_embed = serde_json::json!({
-            "title": format!("{} - {} {}", app_name, event_level_emoji, event_level),
-            "description": format!("```rust\n{}\n```", message),
-            "fields": [
-                {
-                    "name": "Target Span",
-                    "value": format!("`{}::{}`", target, span),
-                    "inline": true
-                },
-                {
-                    "name": "Source",
-                    "value": format!("`{}#L{}`", source_file, source_line),
-                    "inline": true
-                },
-            ],
-            "footer": {
-                "text": app_name
-            },
-            "color": 16711680, // Hex value for "red"
-            "thumbnail": {
-                "url": "https://example.com/error-thumbnail.png"
-            }
-        });
-
-        // Check if metadata exceeds the limit
-        if metadata.len() <= MAX_FIELD_VALUE_CHARS {
-            // Metadata fits within a single field
-            discord_embed["fields"].as_array_mut().unwrap().push(serde_json::json!({
-                "name": "Metadata",
-                "value": format!("```json\n{}\n```", metadata),
-                "inline": false
-            }));
-        } else {
-            // Metadata exceeds the limit, split into multiple fields
-            let mut remaining_metadata = metadata;
-            let mut chunk_number = 1;
-            while !remaining_metadata.is_empty() {
-                let chunk = remaining_metadata
-                    .chars()
-                    .take(MAX_FIELD_VALUE_CHARS)
-                    .collect::<String>();
-
-                remaining_metadata = remaining_metadata.chars().skip(MAX_FIELD_VALUE_CHARS).collect();
-
-                discord_embed["fields"].as_array_mut().unwrap().push(serde_json::json!({
-                    "name": format!("Metadata ({})", chunk_number),
-                    "value": format!("```json\n{}\n```", chunk),
-                    "inline": false
-                }));
-
-                chunk_number += 1;
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
 
-        PayloadMessageType::EmbedNoText(vec![discord_embed])
-    }
-
-    #[cfg(not(feature = "embed"))]
-    fn format_payload(
-        app_name: &str,
-        message: &str,
-        event: &Event,
-        target: &str,
-        span: &str,
-        metadata: String,
-    ) -> PayloadMessageType {
-        let event_level = event.metadata().level().as_str();
-        let source_file = event.metadata().file().unwrap_or("Unknown");
-        let source_line = event.metadata().line().unwrap_or(0);
-        let payload = format!(
-            concat!(
-                "*Trace from {}*\n",
-                "*Event [{}]*: \"{}\"\n",
-                "*Target*: _{}_\n",
-                "*Span*: _{}_\n",
-                "*Metadata*:\n",
-                "```",
-                "{}",
-                "```\n",
-                "*Source*: _{}#L{}_",
-            ),
-            app_name, event_level, message, span, target, metadata, source_file, source_line,
-        );
-        PayloadMessageType::TextNoEmbed(payload)
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "up to 0xffffff")]
+    fn level_colors_larger_than_discord_accepts_are_rejected() {
+        let _ = DiscordLayer::builder("app".to_string(), Regex::new(".*").unwrap().into())
+            .level_color(Level::INFO, 0x1_3498db);
     }
 }
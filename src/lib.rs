@@ -2,16 +2,34 @@
 pub use config::DiscordConfig;
 pub use layer::DiscordLayer;
 pub use layer::DiscordLayerBuilder;
-pub use worker::BackgroundWorker;
+pub use worker::{BackgroundWorker, ShutdownReport, WorkerHandle};
 pub use filters::EventFilters;
+pub use queue::OverflowPolicy;
+pub use route::Route;
+pub use format::{DiscordFormatter, EmbedAuthor, EventView, TextFormatter, TimeDisplay};
+#[cfg(feature = "embed")]
+pub use format::EmbedFormatter;
+pub use message::PayloadMessageType;
+pub use template::{TemplateConfig, TemplateError, TemplateField, TemplateFormatter};
+pub use stats::{FilterStage, WorkerStats};
 
-use crate::worker::WorkerMessage;
-
+mod aggregate;
 mod config;
+mod dead_letter;
 mod layer;
 mod filters;
+mod format;
 mod message;
+mod queue;
+mod rate_limit;
+mod route;
+mod span;
+mod stats;
+mod template;
+#[cfg(test)]
+mod testing;
+mod wal;
 mod worker;
 
-pub(crate) type ChannelSender = tokio::sync::mpsc::UnboundedSender<WorkerMessage>;
-pub(crate) type ChannelReceiver = tokio::sync::mpsc::UnboundedReceiver<WorkerMessage>;
+pub(crate) type ChannelSender = queue::Sender;
+pub(crate) type ChannelReceiver = queue::Receiver;
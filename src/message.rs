@@ -1,4 +1,60 @@
-use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::aggregate::Fingerprint;
+use crate::format::{truncate, MAX_FIELDS};
+
+/// Maximum number of embeds Discord accepts in a single message.
+pub(crate) const MAX_EMBEDS: usize = 10;
+
+/// Maximum number of characters Discord accepts in the content of a message.
+pub(crate) const MAX_CONTENT_CHARS: usize = 2000;
+
+/// Number of characters kept free in messages for the number of occurrences added once their event
+/// repeats.
+pub(crate) const OCCURRENCES_CHARS: usize = 64;
+
+/// Maximum number of characters Discord accepts across all embeds of a single message.
+pub(crate) const MAX_TOTAL_EMBED_CHARS: usize = 6000;
+
+/// Name of the field reporting how many times the event of an edited message occurred.
+const OCCURRENCES_FIELD: &str = "Occurrences";
+
+/// Maximum number of characters Discord accepts in the name of a thread.
+const MAX_THREAD_NAME_CHARS: usize = 100;
+
+/// Where in the channel of its webhook a message is posted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Thread {
+    /// Directly in the channel.
+    #[default]
+    Channel,
+    /// In an existing thread, by ID.
+    Existing(String),
+    /// In the forum post created for the first message of the same kind of event, identified by the
+    /// fingerprint of the event. The post is created with the given name.
+    ForumPost { key: Fingerprint, name: String },
+}
+
+impl Thread {
+    /// A forum post for a kind of event, named after the first event of its kind.
+    pub(crate) fn forum_post(key: Fingerprint, name: &str) -> Self {
+        Thread::ForumPost {
+            key,
+            name: name.chars().take(MAX_THREAD_NAME_CHARS).collect(),
+        }
+    }
+}
+
+/// How a webhook presents itself in the messages it posts, overriding the name and avatar configured
+/// in Discord.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Identity {
+    pub(crate) username: Option<String>,
+    pub(crate) avatar_url: Option<String>,
+}
 
 /// The message sent to Discord. The logged record being "drained" will be
 /// converted into this format.
@@ -10,13 +66,82 @@ pub(crate) struct MessagePayload {
     embeds: Option<Vec<serde_json::Value>>,
     #[serde(skip_serializing)]
     webhook_url: String,
+    /// Identifies the event, if the message is to be edited in place once the event repeats.
+    #[serde(skip_serializing)]
+    fingerprint: Option<Fingerprint>,
+    /// Number of events carried by this message, more than one once batched.
+    #[serde(skip_serializing)]
+    events: usize,
+    /// Sequence numbers of the entries of the write-ahead log holding this message, if it is logged.
+    #[serde(skip_serializing)]
+    sequences: Vec<u64>,
+    #[serde(skip_serializing)]
+    thread: Thread,
+    /// The name of the forum post created by this message, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thread_name: Option<String>,
+    /// Overrides the name of the webhook, if set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    /// Overrides the avatar of the webhook, if set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar_url: Option<String>,
+}
+
+/// A payload as stored on disk, e.g. in the dead-letter spool or the write-ahead log.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct StoredPayload {
+    webhook_url: String,
+    events: usize,
+    #[serde(default)]
+    thread: Thread,
+    /// The body of the request, as sent to Discord.
+    body: Value,
+}
+
+impl StoredPayload {
+    pub(crate) fn new(payload: &MessagePayload) -> serde_json::Result<Self> {
+        Ok(Self {
+            webhook_url: payload.webhook_url.clone(),
+            events: payload.events,
+            thread: payload.thread.clone(),
+            body: serde_json::to_value(payload)?,
+        })
+    }
+
+    pub(crate) fn into_payload(self) -> serde_json::Result<MessagePayload> {
+        #[derive(Deserialize)]
+        struct Body {
+            content: Option<String>,
+            embeds: Option<Vec<Value>>,
+        }
+        let Body { content, embeds } = serde_json::from_value(self.body)?;
+        Ok(MessagePayload {
+            content,
+            embeds,
+            webhook_url: self.webhook_url,
+            fingerprint: None,
+            events: self.events,
+            sequences: Vec::new(),
+            thread: self.thread,
+            thread_name: None,
+            username: None,
+            avatar_url: None,
+        })
+    }
 }
 
-#[allow(dead_code)]
-#[derive(Debug)]
-pub(crate) enum PayloadMessageType {
+/// The content and embeds of a message sent to Discord, as returned by a
+/// [`DiscordFormatter`](crate::DiscordFormatter).
+///
+/// Embeds are JSON objects following Discord's embed structure.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PayloadMessageType {
+    /// A markdown text message.
     TextNoEmbed(String),
+    /// A markdown text message, followed by embeds.
     TextWithEmbed(String, Vec<serde_json::Value>),
+    /// One or more embeds, without text.
     EmbedNoText(Vec<serde_json::Value>),
 }
 
@@ -42,6 +167,13 @@ impl MessagePayload {
             content: text,
             embeds: embed,
             webhook_url,
+            fingerprint: None,
+            events: 1,
+            sequences: Vec::new(),
+            thread: Thread::Channel,
+            thread_name: None,
+            username: None,
+            avatar_url: None,
         }
     }
 }
@@ -50,4 +182,257 @@ impl MessagePayload {
     pub fn webhook_url(&self) -> &str {
         self.webhook_url.as_str()
     }
+
+    /// Mark the message to be edited in place once the event it was created for repeats.
+    pub(crate) fn with_fingerprint(mut self, fingerprint: Fingerprint) -> Self {
+        self.fingerprint = Some(fingerprint);
+        self
+    }
+
+    pub(crate) fn fingerprint(&self) -> Option<Fingerprint> {
+        self.fingerprint
+    }
+
+    /// Record the sequence number of the write-ahead log entry holding this message.
+    pub(crate) fn with_sequence(mut self, sequence: u64) -> Self {
+        self.sequences.push(sequence);
+        self
+    }
+
+    pub(crate) fn sequences(&self) -> &[u64] {
+        &self.sequences
+    }
+
+    /// Post the message in a thread of its webhook's channel.
+    pub(crate) fn with_thread(mut self, thread: Thread) -> Self {
+        self.thread = thread;
+        self
+    }
+
+    pub(crate) fn thread(&self) -> &Thread {
+        &self.thread
+    }
+
+    /// Create a forum post with the given name, or post in the channel if `None`.
+    pub(crate) fn set_thread_name(&mut self, name: Option<String>) {
+        self.thread_name = name;
+    }
+
+    /// Post the message with the given identity, or with the webhook's own if `None`, which must be
+    /// the case when editing the message.
+    pub(crate) fn set_identity(&mut self, identity: Option<&Identity>) {
+        self.username = identity.and_then(|identity| identity.username.clone());
+        self.avatar_url = identity.and_then(|identity| identity.avatar_url.clone());
+    }
+
+    /// A copy of this message, updated with the number of times its event occurred.
+    ///
+    /// The number of occurrences replaces the last field of an embed which already has as many fields
+    /// as Discord accepts.
+    pub(crate) fn with_occurrences(&self, occurrences: usize, last_seen: SystemTime) -> Self {
+        let last_seen = last_seen.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        let occurrences = format!("{}, last seen <t:{}:R>", occurrences, last_seen);
+        let mut edited = self.clone();
+        match edited.embeds.as_mut().and_then(|embeds| embeds.first_mut()) {
+            Some(embed) => {
+                let field = serde_json::json!({
+                    "name": OCCURRENCES_FIELD,
+                    "value": occurrences,
+                    "inline": false
+                });
+                if !embed["fields"].is_array() {
+                    embed["fields"] = Value::Array(Vec::new());
+                }
+                let fields = embed["fields"].as_array_mut().unwrap();
+                match fields.iter().position(|f| f["name"] == OCCURRENCES_FIELD) {
+                    Some(existing) => fields[existing] = field,
+                    None if fields.len() >= MAX_FIELDS => {
+                        fields.truncate(MAX_FIELDS - 1);
+                        fields.push(field);
+                    }
+                    None => fields.push(field),
+                }
+            }
+            None => {
+                let occurrences = format!("\n*{}: {}*", OCCURRENCES_FIELD, occurrences);
+                let content = edited.content.take().unwrap_or_default();
+                let content = truncate(content, MAX_CONTENT_CHARS - occurrences.chars().count()) + &occurrences;
+                edited.content = Some(content);
+            }
+        }
+        edited
+    }
+
+    /// Number of events carried by this message.
+    pub(crate) fn events(&self) -> usize {
+        self.events
+    }
+
+    /// Number of embeds carried by this message.
+    pub(crate) fn embed_count(&self) -> usize {
+        self.embeds.as_ref().map_or(0, Vec::len)
+    }
+
+    /// Append the embeds of another payload to this one, so both are sent in a single request.
+    ///
+    /// Only embed-only messages sent to the same webhook are merged, as long as the result stays
+    /// within Discord's limits on the number and total length of embeds. Otherwise, the other
+    /// payload is handed back unchanged.
+    pub(crate) fn merge(&mut self, other: MessagePayload) -> Result<(), Box<MessagePayload>> {
+        if self.content.is_some()
+            || other.content.is_some()
+            || self.fingerprint.is_some()
+            || other.fingerprint.is_some()
+            || self.webhook_url != other.webhook_url
+            || self.thread != other.thread
+        {
+            return Err(Box::new(other));
+        }
+        let (embeds, other_embeds) = match (&mut self.embeds, &other.embeds) {
+            (Some(embeds), Some(other_embeds)) => (embeds, other_embeds),
+            _ => return Err(Box::new(other)),
+        };
+        let total_chars: usize = embeds.iter().chain(other_embeds).map(embed_chars).sum();
+        if embeds.len() + other_embeds.len() > MAX_EMBEDS || total_chars > MAX_TOTAL_EMBED_CHARS {
+            return Err(Box::new(other));
+        }
+        embeds.extend(other.embeds.into_iter().flatten());
+        self.events += other.events;
+        self.sequences.extend(other.sequences);
+        Ok(())
+    }
+}
+
+/// Number of characters of an embed counting towards Discord's limit on the total size of a message.
+pub(crate) fn embed_chars(embed: &Value) -> usize {
+    let chars = |value: &Value| value.as_str().map_or(0, |s| s.chars().count());
+    let fields: usize = embed["fields"].as_array().map_or(0, |fields| {
+        fields
+            .iter()
+            .map(|field| chars(&field["name"]) + chars(&field["value"]))
+            .sum()
+    });
+    chars(&embed["title"])
+        + chars(&embed["description"])
+        + chars(&embed["footer"]["text"])
+        + chars(&embed["author"]["name"])
+        + fields
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::{payload, WEBHOOK};
+
+    fn with_fields(count: usize) -> MessagePayload {
+        let fields: Vec<Value> = (0..count)
+            .map(|i| json!({ "name": format!("f{}", i), "value": "v", "inline": true }))
+            .collect();
+        let embed = json!({ "title": "boom", "fields": fields });
+        MessagePayload::new(PayloadMessageType::EmbedNoText(vec![embed]), WEBHOOK.to_string())
+    }
+
+    fn field_names(payload: &MessagePayload) -> Vec<String> {
+        payload.embeds.as_ref().unwrap()[0]["fields"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|field| field["name"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn occurrences_are_added_as_the_last_field() {
+        let edited = with_fields(2).with_occurrences(3, UNIX_EPOCH);
+        assert_eq!(field_names(&edited), vec!["f0", "f1", OCCURRENCES_FIELD]);
+        let edited = edited.with_occurrences(4, UNIX_EPOCH);
+        assert_eq!(field_names(&edited), vec!["f0", "f1", OCCURRENCES_FIELD]);
+        let value = &edited.embeds.unwrap()[0]["fields"][2]["value"];
+        assert_eq!(value, "4, last seen <t:0:R>");
+    }
+
+    #[test]
+    fn occurrences_replace_the_last_field_of_full_embeds() {
+        let edited = with_fields(MAX_FIELDS).with_occurrences(3, UNIX_EPOCH);
+        let names = field_names(&edited);
+        assert_eq!(names.len(), MAX_FIELDS);
+        assert_eq!(names[MAX_FIELDS - 2], format!("f{}", MAX_FIELDS - 2));
+        assert_eq!(names[MAX_FIELDS - 1], OCCURRENCES_FIELD);
+    }
+
+    #[test]
+    fn occurrences_are_appended_to_text() {
+        let payload = payload("boom");
+        let edited = payload.with_occurrences(3, UNIX_EPOCH);
+        assert_eq!(edited.content.unwrap(), "boom\n*Occurrences: 3, last seen <t:0:R>*");
+    }
+
+    /// A message of the given number of embeds, each with a title of the given length.
+    fn embeds(count: usize, title_chars: usize) -> MessagePayload {
+        let embeds = (0..count)
+            .map(|_| json!({ "title": "x".repeat(title_chars) }))
+            .collect();
+        MessagePayload::new(PayloadMessageType::EmbedNoText(embeds), WEBHOOK.to_string())
+    }
+
+    fn refuses(mut payload: MessagePayload, other: MessagePayload) {
+        let embeds = payload.embed_count();
+        let other_embeds = other.embed_count();
+        let handed_back = payload.merge(other).unwrap_err();
+        assert_eq!(payload.embed_count(), embeds);
+        assert_eq!(handed_back.embed_count(), other_embeds);
+    }
+
+    #[test]
+    fn merge_appends_embeds_events_and_sequences() {
+        let mut payload = embeds(2, 10).with_sequence(1);
+        let mut other = embeds(3, 10).with_sequence(2).with_sequence(3);
+        other.events = 4;
+        payload.merge(other).unwrap();
+        assert_eq!(payload.embed_count(), 5);
+        assert_eq!(payload.events(), 5);
+        assert_eq!(payload.sequences(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn merge_refuses_text() {
+        refuses(payload("boom"), embeds(1, 10));
+        refuses(embeds(1, 10), payload("boom"));
+        let with_text = MessagePayload::new(
+            PayloadMessageType::TextWithEmbed("boom".to_string(), vec![json!({ "title": "boom" })]),
+            WEBHOOK.to_string(),
+        );
+        refuses(embeds(1, 10), with_text);
+    }
+
+    #[test]
+    fn merge_refuses_messages_edited_in_place() {
+        let fingerprint = Fingerprint::of(&"boom");
+        refuses(embeds(1, 10).with_fingerprint(fingerprint), embeds(1, 10));
+        refuses(embeds(1, 10), embeds(1, 10).with_fingerprint(fingerprint));
+    }
+
+    #[test]
+    fn merge_refuses_other_webhooks_and_threads() {
+        let mut other_webhook = embeds(1, 10);
+        other_webhook.webhook_url = "https://discord.com/api/webhooks/2/token".to_string();
+        refuses(embeds(1, 10), other_webhook);
+        let in_thread = embeds(1, 10).with_thread(Thread::Existing("42".to_string()));
+        refuses(embeds(1, 10), in_thread);
+    }
+
+    #[test]
+    fn merge_stays_within_discord_limits() {
+        let mut payload = embeds(4, 10);
+        payload.merge(embeds(6, 10)).unwrap();
+        assert_eq!(payload.embed_count(), MAX_EMBEDS);
+        refuses(payload, embeds(1, 10));
+
+        let mut payload = embeds(2, 2000);
+        payload.merge(embeds(1, 2000)).unwrap();
+        assert_eq!(payload.embed_count(), 3);
+        refuses(payload, embeds(1, 1));
+    }
 }
@@ -0,0 +1,496 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use tokio::sync::Notify;
+
+use crate::message::{MessagePayload, PayloadMessageType};
+use crate::stats::Counters;
+use crate::wal::WriteAheadLog;
+use crate::worker::WorkerMessage;
+
+/// Determines what happens to an event when the queue of payloads waiting to be sent to Discord is
+/// full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Discard the event that could not be queued.
+    #[default]
+    DropNewest,
+    /// Discard the oldest queued payload to make room for the event.
+    DropOldest,
+    /// Block the thread emitting the event for up to the given duration until there is room in the
+    /// queue, then discard the event.
+    Block(Duration),
+    /// Discard the event, and once the queue has room again, send a single message reporting how
+    /// many events were dropped.
+    Coalesce,
+}
+
+/// Create a queue between the layer and the background worker.
+///
+/// Without a capacity, the queue is unbounded and the overflow policy never applies. Events discarded
+/// by the overflow policy are counted as dropped.
+///
+/// With a write-ahead log, payloads are logged as they are queued, until the background worker
+/// acknowledges them.
+pub(crate) fn channel(
+    capacity: Option<usize>,
+    policy: OverflowPolicy,
+    counters: Arc<Counters>,
+    wal: Option<WriteAheadLog>,
+) -> (Sender, Receiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            messages: VecDeque::new(),
+            dropped: Vec::new(),
+            senders: 1,
+            closed: false,
+        }),
+        available: Notify::new(),
+        space: Condvar::new(),
+        capacity,
+        policy,
+        counters,
+        wal,
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Wakes the receiver once a message is queued, or the last sender is dropped.
+    available: Notify,
+    /// Wakes senders blocked on a full queue once a message is received.
+    space: Condvar,
+    capacity: Option<usize>,
+    policy: OverflowPolicy,
+    counters: Arc<Counters>,
+    wal: Option<WriteAheadLog>,
+}
+
+struct State {
+    messages: VecDeque<WorkerMessage>,
+    /// Number of payloads discarded by `OverflowPolicy::Coalesce` since the last summary, per webhook.
+    dropped: Vec<(String, usize)>,
+    senders: usize,
+    /// Whether the receiver has been dropped.
+    closed: bool,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn is_full(&self, state: &State) -> bool {
+        self.capacity.is_some_and(|capacity| state.messages.len() >= capacity)
+    }
+
+    fn pending_events(&self) -> usize {
+        self.lock().messages.iter().map(WorkerMessage::events).sum()
+    }
+
+    /// Queue a message, logging its payload to the write-ahead log first if the queue is durable.
+    ///
+    /// Payloads which were already logged, e.g. recovered from a previous instance of the process,
+    /// are not logged again.
+    fn push(&self, state: &mut State, message: WorkerMessage) {
+        let message = match (&self.wal, message) {
+            (Some(wal), WorkerMessage::Data(payload)) if payload.sequences().is_empty() => match wal.append(&payload) {
+                Ok(sequence) => WorkerMessage::Data(payload.with_sequence(sequence)),
+                Err(e) => {
+                    tracing::error!(err = %e, "failed to write discord payload to the write-ahead log");
+                    WorkerMessage::Data(payload)
+                }
+            },
+            (_, message) => message,
+        };
+        state.messages.push_back(message);
+    }
+
+    /// Acknowledge that the payloads logged under the given sequence numbers were processed.
+    fn ack(&self, sequences: &[u64]) {
+        if let Some(wal) = &self.wal {
+            if let Err(e) = wal.ack(sequences) {
+                tracing::error!(err = %e, "failed to acknowledge discord payload in the write-ahead log");
+            }
+        }
+    }
+}
+
+/// The sending half of the queue, held by the layer and the background worker's handle.
+pub(crate) struct Sender {
+    shared: Arc<Shared>,
+}
+
+impl Sender {
+    /// Queue a message for the background worker.
+    ///
+    /// Control messages are always queued, while payloads are subject to the overflow policy when
+    /// the queue is full.
+    pub(crate) fn send(&self, message: WorkerMessage) -> Result<(), SendError> {
+        let events = message.events();
+        let result = self.try_send(message);
+        if let Err(SendError::Full) = result {
+            self.shared.counters.record_dropped(events);
+        }
+        result
+    }
+
+    fn try_send(&self, message: WorkerMessage) -> Result<(), SendError> {
+        let mut state = self.shared.lock();
+        if state.closed {
+            return Err(SendError::Closed);
+        }
+        if let WorkerMessage::Data(payload) = &message {
+            if self.shared.is_full(&state) {
+                match self.shared.policy {
+                    OverflowPolicy::DropNewest => return Err(SendError::Full),
+                    OverflowPolicy::DropOldest => {
+                        let oldest = state.messages.iter().position(|m| matches!(m, WorkerMessage::Data(_)));
+                        match oldest {
+                            Some(index) => {
+                                if let Some(WorkerMessage::Data(evicted)) = state.messages.remove(index) {
+                                    self.shared.counters.record_dropped(evicted.events());
+                                    self.shared.ack(evicted.sequences());
+                                }
+                            }
+                            None => return Err(SendError::Full),
+                        }
+                    }
+                    OverflowPolicy::Block(timeout) => {
+                        let deadline = Instant::now() + timeout;
+                        while self.shared.is_full(&state) && !state.closed {
+                            let remaining = deadline.saturating_duration_since(Instant::now());
+                            if remaining.is_zero() {
+                                return Err(SendError::Full);
+                            }
+                            state = self
+                                .shared
+                                .space
+                                .wait_timeout(state, remaining)
+                                .unwrap_or_else(|e| e.into_inner())
+                                .0;
+                        }
+                        if state.closed {
+                            return Err(SendError::Closed);
+                        }
+                    }
+                    OverflowPolicy::Coalesce => {
+                        let webhook_url = payload.webhook_url();
+                        match state.dropped.iter_mut().find(|(url, _)| url == webhook_url) {
+                            Some((_, count)) => *count += 1,
+                            None => state.dropped.push((webhook_url.to_string(), 1)),
+                        }
+                        return Err(SendError::Full);
+                    }
+                }
+            }
+        }
+        self.shared.push(&mut state, message);
+        drop(state);
+        self.shared.available.notify_one();
+        Ok(())
+    }
+}
+
+impl Sender {
+    /// Queue a message for the background worker, regardless of the queue's capacity.
+    pub(crate) fn force_send(&self, message: WorkerMessage) -> Result<(), SendError> {
+        let mut state = self.shared.lock();
+        if state.closed {
+            return Err(SendError::Closed);
+        }
+        self.shared.push(&mut state, message);
+        drop(state);
+        self.shared.available.notify_one();
+        Ok(())
+    }
+
+    /// Number of events carried by the payloads still waiting in the queue.
+    pub(crate) fn pending_events(&self) -> usize {
+        self.shared.pending_events()
+    }
+
+    /// A handle to observe the number of events waiting in the queue, which does not keep the queue
+    /// open.
+    pub(crate) fn depth(&self) -> QueueDepth {
+        QueueDepth {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Clone for Sender {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            self.shared.available.notify_one();
+        }
+    }
+}
+
+/// Observes the number of events waiting in the queue.
+#[derive(Clone)]
+pub(crate) struct QueueDepth {
+    shared: Arc<Shared>,
+}
+
+impl QueueDepth {
+    /// Number of events carried by the payloads still waiting in the queue.
+    pub(crate) fn events(&self) -> usize {
+        self.shared.pending_events()
+    }
+}
+
+/// The receiving half of the queue, owned by the background worker.
+pub(crate) struct Receiver {
+    shared: Arc<Shared>,
+}
+
+impl Receiver {
+    /// Receive the next message, or `None` once the queue is empty and every sender was dropped.
+    pub(crate) async fn recv(&mut self) -> Option<WorkerMessage> {
+        loop {
+            {
+                let mut state = self.shared.lock();
+                if let Some(summary) = self.take_dropped_summary(&mut state) {
+                    return Some(WorkerMessage::Data(summary));
+                }
+                if let Some(message) = state.messages.pop_front() {
+                    self.shared.space.notify_one();
+                    return Some(message);
+                }
+                if state.senders == 0 {
+                    return None;
+                }
+            }
+            self.shared.available.notified().await;
+        }
+    }
+
+    /// Acknowledge that the payloads logged under the given sequence numbers were processed, so
+    /// that they are not sent again by the next instance of the process.
+    pub(crate) fn ack(&self, sequences: &[u64]) {
+        self.shared.ack(sequences);
+    }
+
+    /// Build the message reporting events dropped by `OverflowPolicy::Coalesce`, once the queue has
+    /// drained to half its capacity.
+    fn take_dropped_summary(&self, state: &mut State) -> Option<MessagePayload> {
+        let capacity = self.shared.capacity?;
+        if state.dropped.is_empty() || state.messages.len() > capacity / 2 {
+            return None;
+        }
+        let (webhook_url, count) = state.dropped.remove(0);
+        let text = format!(
+            ":warning: {} event{} dropped because the queue to Discord was full",
+            count,
+            if count == 1 { " was" } else { "s were" }
+        );
+        Some(MessagePayload::new(PayloadMessageType::TextNoEmbed(text), webhook_url))
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.space.notify_all();
+    }
+}
+
+/// The reason a message could not be queued for the background worker.
+#[derive(Debug)]
+pub(crate) enum SendError {
+    /// The queue is full, and the overflow policy discarded the payload.
+    Full,
+    /// The background worker has stopped.
+    Closed,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Full => write!(f, "queue is full"),
+            SendError::Closed => write!(f, "background worker has stopped"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::WorkerStats;
+    use crate::testing::{content, payload, TempFile};
+
+    fn bounded(capacity: usize, policy: OverflowPolicy) -> (Sender, Receiver, WorkerStats) {
+        bounded_with_log(capacity, policy, None)
+    }
+
+    fn bounded_with_log(
+        capacity: usize,
+        policy: OverflowPolicy,
+        wal: Option<WriteAheadLog>,
+    ) -> (Sender, Receiver, WorkerStats) {
+        let counters = Arc::new(Counters::default());
+        let (sender, receiver) = channel(Some(capacity), policy, counters.clone(), wal);
+        let stats = WorkerStats {
+            counters,
+            queue: sender.depth(),
+        };
+        (sender, receiver, stats)
+    }
+
+    fn data(text: &str) -> WorkerMessage {
+        WorkerMessage::Data(payload(text))
+    }
+
+    fn text(message: Option<WorkerMessage>) -> String {
+        match message {
+            Some(WorkerMessage::Data(payload)) => content(&payload),
+            other => panic!("expected a payload, got {:?}", other),
+        }
+    }
+
+    fn open(log: &TempFile) -> (WriteAheadLog, Vec<MessagePayload>) {
+        WriteAheadLog::open(log.path().to_path_buf()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn drop_newest_discards_payloads_once_full() {
+        let (sender, mut receiver, stats) = bounded(2, OverflowPolicy::DropNewest);
+        sender.send(data("a")).unwrap();
+        sender.send(data("b")).unwrap();
+        assert!(matches!(sender.send(data("c")), Err(SendError::Full)));
+        // Control messages are queued regardless of the capacity.
+        sender.send(WorkerMessage::Shutdown).unwrap();
+        assert_eq!(stats.dropped(), 1);
+        assert_eq!(stats.queue_depth(), 2);
+        assert_eq!(text(receiver.recv().await), "a");
+        assert_eq!(text(receiver.recv().await), "b");
+        assert!(matches!(receiver.recv().await, Some(WorkerMessage::Shutdown)));
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_evicts_the_oldest_payload() {
+        let log = TempFile::new("queue");
+        let (wal, _) = open(&log);
+        let (sender, mut receiver, stats) = bounded_with_log(2, OverflowPolicy::DropOldest, Some(wal));
+        sender.send(data("a")).unwrap();
+        sender.send(data("b")).unwrap();
+        sender.send(data("c")).unwrap();
+        assert_eq!(stats.dropped(), 1);
+        assert_eq!(text(receiver.recv().await), "b");
+        assert_eq!(text(receiver.recv().await), "c");
+        // The evicted payload was acknowledged, and is not sent again by the next process.
+        let (_, recovered) = open(&log);
+        let recovered: Vec<String> = recovered.iter().map(content).collect();
+        assert_eq!(recovered, vec!["b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_control_messages() {
+        let (sender, mut receiver, _) = bounded(1, OverflowPolicy::DropOldest);
+        sender.send(WorkerMessage::Shutdown).unwrap();
+        assert!(matches!(sender.send(data("a")), Err(SendError::Full)));
+        assert!(matches!(receiver.recv().await, Some(WorkerMessage::Shutdown)));
+    }
+
+    #[test]
+    fn block_gives_up_once_the_timeout_elapses() {
+        let timeout = Duration::from_millis(50);
+        let (sender, _receiver, stats) = bounded(1, OverflowPolicy::Block(timeout));
+        sender.send(data("a")).unwrap();
+        let started = Instant::now();
+        assert!(matches!(sender.send(data("b")), Err(SendError::Full)));
+        assert!(started.elapsed() >= timeout);
+        assert_eq!(stats.dropped(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn block_waits_for_room_in_the_queue() {
+        let (sender, mut receiver, stats) = bounded(1, OverflowPolicy::Block(Duration::from_secs(10)));
+        sender.send(data("a")).unwrap();
+        let blocked = std::thread::spawn(move || sender.send(data("b")));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!blocked.is_finished());
+        assert_eq!(text(receiver.recv().await), "a");
+        blocked.join().unwrap().unwrap();
+        assert_eq!(text(receiver.recv().await), "b");
+        assert_eq!(stats.dropped(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn block_stops_waiting_once_the_receiver_is_dropped() {
+        let (sender, receiver, _) = bounded(1, OverflowPolicy::Block(Duration::from_secs(10)));
+        sender.send(data("a")).unwrap();
+        let blocked = std::thread::spawn(move || sender.send(data("b")));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(receiver);
+        assert!(matches!(blocked.join().unwrap(), Err(SendError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn coalesce_reports_dropped_payloads_once_half_empty() {
+        let (sender, mut receiver, stats) = bounded(2, OverflowPolicy::Coalesce);
+        sender.send(data("a")).unwrap();
+        sender.send(data("b")).unwrap();
+        assert!(matches!(sender.send(data("c")), Err(SendError::Full)));
+        assert!(matches!(sender.send(data("d")), Err(SendError::Full)));
+        assert_eq!(stats.dropped(), 2);
+        assert_eq!(text(receiver.recv().await), "a");
+        assert_eq!(
+            text(receiver.recv().await),
+            ":warning: 2 events were dropped because the queue to Discord was full"
+        );
+        assert_eq!(text(receiver.recv().await), "b");
+        sender.send(data("e")).unwrap();
+        assert_eq!(text(receiver.recv().await), "e");
+    }
+
+    #[tokio::test]
+    async fn receiving_waits_for_a_message() {
+        let (sender, mut receiver) = channel(None, OverflowPolicy::default(), Arc::default(), None);
+        let received = tokio::spawn(async move { text(receiver.recv().await) });
+        tokio::task::yield_now().await;
+        sender.send(data("a")).unwrap();
+        assert_eq!(received.await.unwrap(), "a");
+    }
+
+    #[tokio::test]
+    async fn receiving_ends_once_every_sender_is_dropped() {
+        let (sender, mut receiver) = channel(None, OverflowPolicy::default(), Arc::default(), None);
+        let other = sender.clone();
+        sender.send(data("a")).unwrap();
+        drop(sender);
+        let received = tokio::spawn(async move { (text(receiver.recv().await), receiver.recv().await.is_none()) });
+        tokio::task::yield_now().await;
+        drop(other);
+        assert_eq!(received.await.unwrap(), ("a".to_string(), true));
+    }
+
+    #[test]
+    fn sending_fails_once_the_receiver_is_dropped() {
+        let (sender, receiver) = channel(Some(1), OverflowPolicy::default(), Arc::default(), None);
+        drop(receiver);
+        assert!(matches!(sender.send(data("a")), Err(SendError::Closed)));
+        assert!(matches!(
+            sender.force_send(WorkerMessage::Shutdown),
+            Err(SendError::Closed)
+        ));
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+use reqwest::header::HeaderMap;
+use serde::Deserialize;
+use tokio::time::Instant;
+
+/// Fallback delay used when Discord responds with a 429 but provides no usable reset information.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Tracks the Discord rate-limit buckets observed by the worker, so that requests can be held back
+/// until a bucket resets instead of being rejected.
+///
+/// Each webhook has its own bucket, reported through the `X-RateLimit-Remaining` and
+/// `X-RateLimit-Reset-After` headers of every response. Hitting the global limit blocks requests
+/// to all webhooks.
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+    /// The instant at which each exhausted bucket resets, keyed by webhook URL.
+    buckets: HashMap<String, Instant>,
+    /// The instant at which the global rate limit resets, if it has been hit.
+    global: Option<Instant>,
+}
+
+/// The body of a Discord 429 response.
+#[derive(Debug, Deserialize)]
+struct RateLimitResponse {
+    /// Number of seconds to wait before submitting another request.
+    retry_after: f64,
+    /// Whether this is a global rate limit, rather than one for the webhook's bucket.
+    #[serde(default)]
+    global: bool,
+}
+
+impl RateLimiter {
+    /// Wait until neither the global rate limit nor the bucket of the given webhook is exhausted.
+    pub(crate) async fn acquire(&mut self, webhook_url: &str) {
+        if let Some(until) = self.reset_at(webhook_url) {
+            tokio::time::sleep_until(until).await;
+        }
+        let now = Instant::now();
+        self.global = self.global.filter(|&until| until > now);
+        self.buckets.retain(|_, until| *until > now);
+    }
+
+    /// The instant at which a request to the given webhook may be sent, if it must be held back.
+    fn reset_at(&self, webhook_url: &str) -> Option<Instant> {
+        let now = Instant::now();
+        self.global
+            .into_iter()
+            .chain(self.buckets.get(webhook_url).copied())
+            .filter(|&until| until > now)
+            .max()
+    }
+
+    /// Update the webhook's bucket from the rate-limit headers of a response.
+    pub(crate) fn update(&mut self, webhook_url: &str, headers: &HeaderMap) {
+        let remaining = header::<u64>(headers, "x-ratelimit-remaining");
+        let reset_after = header::<f64>(headers, "x-ratelimit-reset-after");
+        match (remaining, reset_after) {
+            (Some(0), Some(reset_after)) => {
+                self.buckets
+                    .insert(webhook_url.to_string(), Instant::now() + seconds(reset_after));
+            }
+            _ => {
+                self.buckets.remove(webhook_url);
+            }
+        }
+    }
+
+    /// Record a 429 response for the given webhook, holding back its requests until the limit resets.
+    ///
+    /// The `retry_after` of the JSON body takes precedence, falling back to the `Retry-After` and
+    /// `X-RateLimit-Reset-After` headers.
+    pub(crate) fn limited(&mut self, webhook_url: &str, headers: &HeaderMap, body: &str) {
+        let (retry_after, global) = match serde_json::from_str::<RateLimitResponse>(body) {
+            Ok(response) => (seconds(response.retry_after), response.global),
+            Err(_) => {
+                let retry_after = header::<f64>(headers, "retry-after")
+                    .or_else(|| header::<f64>(headers, "x-ratelimit-reset-after"))
+                    .map(seconds)
+                    .unwrap_or(DEFAULT_RETRY_AFTER);
+                let global = header::<bool>(headers, "x-ratelimit-global").unwrap_or(false);
+                (retry_after, global)
+            }
+        };
+        let until = Instant::now() + retry_after;
+        if global {
+            self.global = Some(self.global.map_or(until, |current| current.max(until)));
+        } else {
+            self.buckets.insert(webhook_url.to_string(), until);
+        }
+    }
+}
+
+/// Parse the value of a header, ignoring it if missing or malformed.
+fn header<T: FromStr>(headers: &HeaderMap, name: &str) -> Option<T> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+/// Convert a number of seconds reported by Discord into a duration, clamping invalid values.
+fn seconds(value: f64) -> Duration {
+    if value.is_finite() && value > 0.0 {
+        Duration::from_secs_f64(value)
+    } else {
+        Duration::ZERO
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::WEBHOOK;
+    use reqwest::header::HeaderValue;
+
+    const OTHER_WEBHOOK: &str = "https://discord.com/api/webhooks/2/token";
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn body_retry_after_holds_back_the_webhook_only() {
+        let mut limiter = RateLimiter::default();
+        limiter.limited(WEBHOOK, &HeaderMap::new(), r#"{"retry_after": 2.5, "global": false}"#);
+        assert_eq!(
+            limiter.reset_at(WEBHOOK),
+            Some(Instant::now() + Duration::from_millis(2500))
+        );
+        assert_eq!(limiter.reset_at(OTHER_WEBHOOK), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn global_limit_holds_back_every_webhook() {
+        let mut limiter = RateLimiter::default();
+        limiter.limited(WEBHOOK, &HeaderMap::new(), r#"{"retry_after": 3, "global": true}"#);
+        let until = Some(Instant::now() + Duration::from_secs(3));
+        assert_eq!(limiter.reset_at(WEBHOOK), until);
+        assert_eq!(limiter.reset_at(OTHER_WEBHOOK), until);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn body_takes_precedence_over_headers() {
+        let mut limiter = RateLimiter::default();
+        let headers = headers(&[("retry-after", "10")]);
+        limiter.limited(WEBHOOK, &headers, r#"{"retry_after": 0.5}"#);
+        assert_eq!(
+            limiter.reset_at(WEBHOOK),
+            Some(Instant::now() + Duration::from_millis(500))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn malformed_body_falls_back_to_headers() {
+        let mut limiter = RateLimiter::default();
+        let headers = headers(&[("retry-after", " 4 "), ("x-ratelimit-reset-after", "7")]);
+        limiter.limited(WEBHOOK, &headers, "<html>Too Many Requests</html>");
+        assert_eq!(limiter.reset_at(WEBHOOK), Some(Instant::now() + Duration::from_secs(4)));
+
+        let mut limiter = RateLimiter::default();
+        let headers = self::headers(&[("x-ratelimit-reset-after", "1.25"), ("x-ratelimit-global", "true")]);
+        limiter.limited(WEBHOOK, &headers, "");
+        let until = Some(Instant::now() + Duration::from_millis(1250));
+        assert_eq!(limiter.reset_at(OTHER_WEBHOOK), until);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn missing_reset_information_uses_the_default_delay() {
+        let mut limiter = RateLimiter::default();
+        limiter.limited(WEBHOOK, &headers(&[("retry-after", "soon")]), "{}");
+        assert_eq!(limiter.reset_at(WEBHOOK), Some(Instant::now() + DEFAULT_RETRY_AFTER));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn global_limit_is_never_shortened() {
+        let mut limiter = RateLimiter::default();
+        limiter.limited(WEBHOOK, &HeaderMap::new(), r#"{"retry_after": 5, "global": true}"#);
+        limiter.limited(WEBHOOK, &HeaderMap::new(), r#"{"retry_after": 1, "global": true}"#);
+        assert_eq!(
+            limiter.reset_at(OTHER_WEBHOOK),
+            Some(Instant::now() + Duration::from_secs(5))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn exhausted_bucket_is_held_back_until_reset() {
+        let mut limiter = RateLimiter::default();
+        let exhausted = headers(&[("x-ratelimit-remaining", "0"), ("x-ratelimit-reset-after", "2")]);
+        limiter.update(WEBHOOK, &exhausted);
+        assert_eq!(limiter.reset_at(WEBHOOK), Some(Instant::now() + Duration::from_secs(2)));
+
+        let start = Instant::now();
+        limiter.acquire(WEBHOOK).await;
+        assert_eq!(Instant::now() - start, Duration::from_secs(2));
+        assert_eq!(limiter.reset_at(WEBHOOK), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn remaining_requests_clear_the_bucket() {
+        let mut limiter = RateLimiter::default();
+        let exhausted = headers(&[("x-ratelimit-remaining", "0"), ("x-ratelimit-reset-after", "2")]);
+        limiter.update(WEBHOOK, &exhausted);
+        let available = headers(&[("x-ratelimit-remaining", "4"), ("x-ratelimit-reset-after", "2")]);
+        limiter.update(WEBHOOK, &available);
+        assert_eq!(limiter.reset_at(WEBHOOK), None);
+    }
+
+    #[test]
+    fn invalid_seconds_are_clamped() {
+        assert_eq!(seconds(-1.0), Duration::ZERO);
+        assert_eq!(seconds(f64::NAN), Duration::ZERO);
+        assert_eq!(seconds(f64::INFINITY), Duration::ZERO);
+        assert_eq!(seconds(0.25), Duration::from_millis(250));
+    }
+}
@@ -0,0 +1,160 @@
+use std::fmt;
+use std::sync::Arc;
+
+use tracing::Level;
+
+use crate::config::ThreadMode;
+use crate::filters::{EventFilters, Filter};
+use crate::format::DiscordFormatter;
+
+/// A named destination for events, with its own webhook, filters and format.
+///
+/// Every route is evaluated independently of the layer's own filters, which only decide whether an
+/// event is sent to the layer's configured webhook. An event is sent to every route accepting it.
+///
+/// ```rust,no_run
+/// # use regex::Regex;
+/// # use tracing::Level;
+/// # use tracing_layer_discord::{Route, TextFormatter};
+/// let payments = Route::new("payments", "https://discord.com/api/webhooks/...")
+///     .target_filters(Regex::new("^payments").unwrap().into())
+///     .level(Level::WARN)
+///     .formatter(TextFormatter::new());
+/// ```
+#[derive(Clone)]
+pub struct Route {
+    pub(crate) name: String,
+    pub(crate) webhook_url: String,
+    target_filters: Option<EventFilters>,
+    message_filters: Option<EventFilters>,
+    event_by_field_filters: Option<EventFilters>,
+    level: Option<Level>,
+    /// Formats the events of this route, if not the layer's formatter.
+    pub(crate) formatter: Option<Arc<dyn DiscordFormatter>>,
+    pub(crate) thread: ThreadMode,
+}
+
+impl Route {
+    /// Create a route sending every event to the given webhook, formatted by the layer's formatter.
+    pub fn new(name: impl Into<String>, webhook_url: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            webhook_url: webhook_url.into(),
+            target_filters: None,
+            message_filters: None,
+            event_by_field_filters: None,
+            level: None,
+            formatter: None,
+            thread: ThreadMode::Channel,
+        }
+    }
+
+    /// Filter events by their target, with the same semantics as the layer's target filters.
+    pub fn target_filters(mut self, filters: EventFilters) -> Self {
+        self.target_filters = Some(filters);
+        self
+    }
+
+    /// Filter events by their message, with the same semantics as the layer's message filters.
+    pub fn message_filters(mut self, filters: EventFilters) -> Self {
+        self.message_filters = Some(filters);
+        self
+    }
+
+    /// Filter events by their fields, with the same semantics as the layer's event-by-field filters.
+    pub fn event_by_field_filters(mut self, filters: EventFilters) -> Self {
+        self.event_by_field_filters = Some(filters);
+        self
+    }
+
+    /// Only send events at the given level or more severe.
+    pub fn level(mut self, level: Level) -> Self {
+        self.level = Some(level);
+        self
+    }
+
+    /// Format the events sent to this route with the given formatter, rather than the layer's.
+    pub fn formatter(mut self, formatter: impl DiscordFormatter + 'static) -> Self {
+        self.formatter = Some(Arc::new(formatter));
+        self
+    }
+
+    /// Post the messages of this route in an existing thread of its webhook's channel.
+    pub fn thread_id(mut self, thread_id: impl Into<String>) -> Self {
+        self.thread = ThreadMode::Existing(thread_id.into());
+        self
+    }
+
+    /// Create a forum post for each kind of event sent to this route, whose webhook's channel must
+    /// be a forum, and post the later occurrences of the event into it.
+    pub fn forum_post_per_event(mut self) -> Self {
+        self.thread = ThreadMode::ForumPostPerEvent;
+        self
+    }
+
+    /// Whether an event with the given level, target, message and field names is sent to this
+    /// route.
+    pub(crate) fn accepts(&self, level: &Level, target: &str, message: &str, keys: &[&str]) -> bool {
+        // More verbose levels compare greater.
+        let severe_enough = match self.level {
+            Some(threshold) => *level <= threshold,
+            None => true,
+        };
+        severe_enough
+            && self.target_filters.process(target).is_ok()
+            && self.message_filters.process(message).is_ok()
+            && keys.iter().all(|key| self.event_by_field_filters.process(key).is_ok())
+    }
+}
+
+impl fmt::Debug for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Route")
+            .field("name", &self.name)
+            .field("webhook_url", &self.webhook_url)
+            .field("target_filters", &self.target_filters)
+            .field("message_filters", &self.message_filters)
+            .field("event_by_field_filters", &self.event_by_field_filters)
+            .field("level", &self.level)
+            .field("thread", &self.thread)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+
+    use super::*;
+    use crate::testing::WEBHOOK;
+
+    fn accepts(route: &Route, level: Level) -> bool {
+        route.accepts(&level, "server", "boom", &[])
+    }
+
+    #[test]
+    fn level_accepts_the_threshold_and_more_severe_levels() {
+        let route = Route::new("ops", WEBHOOK).level(Level::WARN);
+        assert!(accepts(&route, Level::ERROR));
+        assert!(accepts(&route, Level::WARN));
+        assert!(!accepts(&route, Level::INFO));
+        assert!(!accepts(&route, Level::TRACE));
+    }
+
+    #[test]
+    fn every_level_is_accepted_without_a_threshold() {
+        let route = Route::new("ops", WEBHOOK);
+        assert!(accepts(&route, Level::ERROR));
+        assert!(accepts(&route, Level::TRACE));
+    }
+
+    #[test]
+    fn level_and_filters_must_all_accept() {
+        let route = Route::new("ops", WEBHOOK)
+            .target_filters(Regex::new("^server").unwrap().into())
+            .level(Level::WARN);
+        assert!(route.accepts(&Level::ERROR, "server::http", "boom", &[]));
+        assert!(!route.accepts(&Level::ERROR, "client", "boom", &[]));
+        assert!(!route.accepts(&Level::INFO, "server::http", "boom", &[]));
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::Write;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+use tokio::time::Instant;
+use tracing::Level;
+use tracing_bunyan_formatter::JsonStorage;
+
+use crate::aggregate::Fingerprint;
+use crate::message::Thread;
+
+/// The levels of events, from the most to the least severe.
+const LEVELS: [Level; 5] = [Level::ERROR, Level::WARN, Level::INFO, Level::DEBUG, Level::TRACE];
+
+/// The fields recorded on a span itself, without those of its ancestors, stored in the extensions of
+/// the span.
+#[derive(Debug, Default)]
+pub(crate) struct SpanFields(pub(crate) JsonStorage<'static>);
+
+/// Number of events emitted within a span, by level.
+#[derive(Debug, Default)]
+pub(crate) struct LevelCounts([AtomicUsize; 5]);
+
+impl LevelCounts {
+    /// Count an event of the given level.
+    pub(crate) fn record(&self, level: &Level) {
+        if let Some(index) = LEVELS.iter().position(|l| l == level) {
+            self.0[index].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn get(&self, level: &Level) -> usize {
+        LEVELS
+            .iter()
+            .position(|l| l == level)
+            .map_or(0, |index| self.0[index].load(Ordering::Relaxed))
+    }
+}
+
+/// The forum post grouping the events of a span, stored in the extensions of the span.
+#[derive(Debug)]
+pub(crate) struct SpanThread {
+    /// Identifies the span among every span ever opened, unlike its ID which may be reused.
+    pub(crate) key: Fingerprint,
+    pub(crate) thread: Thread,
+    opened: Instant,
+    /// Number of events posted in the thread.
+    pub(crate) events: LevelCounts,
+}
+
+impl SpanThread {
+    /// The key of the first span given a forum post, which is unique to the process so that the keys
+    /// of its spans never match those of payloads recovered from a previous process, e.g. from a
+    /// durable queue.
+    pub(crate) fn first_key() -> u64 {
+        let mut hasher = DefaultHasher::new();
+        std::process::id().hash(&mut hasher);
+        SystemTime::now().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Open a forum post for the span with the given unique key and name.
+    pub(crate) fn new(key: u64, name: &str) -> Self {
+        let key = Fingerprint::of(&("span", key));
+        Self {
+            key,
+            thread: Thread::forum_post(key, name),
+            opened: Instant::now(),
+            events: LevelCounts::default(),
+        }
+    }
+
+    /// Summarize how long the span was open for, and how many events of each level were posted.
+    pub(crate) fn summary(&self, app_name: &str, span_name: &str) -> String {
+        let mut summary = format!(
+            ":stop_button: **{}** span `{}` closed after {}",
+            app_name,
+            span_name,
+            format_duration(self.opened.elapsed())
+        );
+        let counts: Vec<String> = LEVELS
+            .iter()
+            .map(|level| (level, self.events.get(level)))
+            .filter(|(_, count)| *count > 0)
+            .map(|(level, count)| format!("{}: {}", level, count))
+            .collect();
+        if counts.is_empty() {
+            summary.push_str("\nNo events");
+        } else {
+            let _ = write!(summary, "\nEvents: {}", counts.join(", "));
+        }
+        summary
+    }
+}
+
+/// How long a span was busy, i.e. entered, or idle, along with the events emitted within it, stored
+/// in the extensions of the spans whose closing is notified or which are alerted on when slow.
+#[derive(Debug)]
+pub(crate) struct SpanTimings {
+    /// Whether to notify when the span closes, however long it took.
+    pub(crate) notify: bool,
+    /// The duration over which the span is alerted on as slow, if any.
+    slow_after: Option<Duration>,
+    opened: Instant,
+    /// The instant the span was last entered or exited.
+    last: Instant,
+    busy: Duration,
+    idle: Duration,
+    /// Number of events emitted within the span, including within its descendants.
+    pub(crate) events: LevelCounts,
+}
+
+impl SpanTimings {
+    pub(crate) fn new(notify: bool, slow_after: Option<Duration>) -> Self {
+        let now = Instant::now();
+        Self {
+            notify,
+            slow_after,
+            opened: now,
+            last: now,
+            busy: Duration::ZERO,
+            idle: Duration::ZERO,
+            events: LevelCounts::default(),
+        }
+    }
+
+    /// Account for the time elapsed since the span was last exited as idle.
+    pub(crate) fn enter(&mut self) {
+        let now = Instant::now();
+        self.idle += now - self.last;
+        self.last = now;
+    }
+
+    /// Account for the time elapsed since the span was last entered as busy.
+    pub(crate) fn exit(&mut self) {
+        let now = Instant::now();
+        self.busy += now - self.last;
+        self.last = now;
+    }
+
+    /// The most severe level of the events emitted within the span, from WARN up, or INFO.
+    pub(crate) fn level(&self) -> Level {
+        [Level::ERROR, Level::WARN]
+            .iter()
+            .copied()
+            .find(|level| self.events.get(level) > 0)
+            .unwrap_or(Level::INFO)
+    }
+
+    /// Account for the time elapsed since the span was last exited as idle, once it closed.
+    pub(crate) fn close(&mut self) {
+        self.enter();
+    }
+
+    /// Notify that the span closed, e.g. "nightly_import finished in 14m32s with 3 warnings",
+    /// along with how long it was busy or idle.
+    pub(crate) fn notification(&self, app_name: &str, span_name: &str) -> String {
+        let emoji = match self.level() {
+            Level::ERROR => ":x:",
+            Level::WARN => ":warning:",
+            _ => ":white_check_mark:",
+        };
+        let mut notification = format!(
+            "{} **{}** span `{}` finished in {}",
+            emoji,
+            app_name,
+            span_name,
+            format_duration(self.opened.elapsed())
+        );
+        let counts: Vec<String> = [(Level::ERROR, "error"), (Level::WARN, "warning")]
+            .iter()
+            .map(|(level, noun)| (self.events.get(level), noun))
+            .filter(|(count, _)| *count > 0)
+            .map(|(count, noun)| format!("{} {}{}", count, noun, if count == 1 { "" } else { "s" }))
+            .collect();
+        if !counts.is_empty() {
+            let _ = write!(notification, " with {}", counts.join(" and "));
+        }
+        notification.push('\n');
+        notification.push_str(&self.busy_idle());
+        notification
+    }
+
+    /// Alert that the span took longer than its threshold, if it did, e.g. "http_request took
+    /// 6.20s, over its 5.00s threshold", along with how long it was busy or idle.
+    pub(crate) fn slow_alert(&self, app_name: &str, span_name: &str) -> Option<String> {
+        let threshold = self.slow_after?;
+        let elapsed = self.opened.elapsed();
+        if elapsed <= threshold {
+            return None;
+        }
+        Some(format!(
+            ":snail: **{}** span `{}` took {}, over its {} threshold\n{}",
+            app_name,
+            span_name,
+            format_duration(elapsed),
+            format_duration(threshold),
+            self.busy_idle()
+        ))
+    }
+
+    fn busy_idle(&self) -> String {
+        format!(
+            "Busy: {}, idle: {}",
+            format_duration(self.busy),
+            format_duration(self.idle)
+        )
+    }
+}
+
+/// Format a duration for humans, e.g. `14m32s`, or `1.50s` under a minute.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{:.2?}", duration),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m{:02}s", secs / 3600, secs % 3600 / 60, secs % 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::advance;
+
+    use super::*;
+
+    fn timings(slow_after: Option<Duration>) -> SpanTimings {
+        SpanTimings::new(true, slow_after)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn time_between_entering_and_exiting_is_busy_and_the_rest_idle() {
+        let mut timings = timings(None);
+        advance(Duration::from_secs(1)).await;
+        timings.enter();
+        advance(Duration::from_secs(2)).await;
+        timings.exit();
+        advance(Duration::from_secs(3)).await;
+        timings.enter();
+        advance(Duration::from_secs(4)).await;
+        timings.exit();
+        advance(Duration::from_secs(5)).await;
+        timings.close();
+        assert_eq!(
+            timings.notification("app", "import"),
+            ":white_check_mark: **app** span `import` finished in 15.00s\nBusy: 6.00s, idle: 9.00s"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn level_is_the_most_severe_from_warn_up() {
+        let timings = timings(None);
+        timings.events.record(&Level::DEBUG);
+        timings.events.record(&Level::INFO);
+        assert_eq!(timings.level(), Level::INFO);
+        timings.events.record(&Level::WARN);
+        assert_eq!(timings.level(), Level::WARN);
+        timings.events.record(&Level::ERROR);
+        timings.events.record(&Level::WARN);
+        assert_eq!(timings.level(), Level::ERROR);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn notification_counts_errors_and_warnings() {
+        let timings = timings(None);
+        timings.events.record(&Level::WARN);
+        let notification = timings.notification("app", "import");
+        assert!(notification.starts_with(":warning: **app** span `import` finished in 0.00ns with 1 warning\n"));
+
+        for _ in 0..3 {
+            timings.events.record(&Level::ERROR);
+        }
+        timings.events.record(&Level::WARN);
+        timings.events.record(&Level::WARN);
+        let notification = timings.notification("app", "import");
+        assert!(notification.starts_with(":x: **app** span `import` finished in 0.00ns with 3 errors and 3 warnings\n"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_alert_fires_past_the_threshold_only() {
+        let slow = timings(Some(Duration::from_secs(5)));
+        advance(Duration::from_secs(5)).await;
+        assert_eq!(slow.slow_alert("app", "http_request"), None);
+        advance(Duration::from_millis(1200)).await;
+        assert_eq!(
+            slow.slow_alert("app", "http_request").unwrap(),
+            ":snail: **app** span `http_request` took 6.20s, over its 5.00s threshold\nBusy: 0.00ns, idle: 0.00ns"
+        );
+        assert_eq!(timings(None).slow_alert("app", "http_request"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn thread_summary_counts_events_by_level() {
+        let thread = SpanThread::new(1, "import");
+        advance(Duration::from_secs(2)).await;
+        assert_eq!(
+            thread.summary("app", "import"),
+            ":stop_button: **app** span `import` closed after 2.00s\nNo events"
+        );
+        thread.events.record(&Level::WARN);
+        thread.events.record(&Level::ERROR);
+        thread.events.record(&Level::WARN);
+        assert_eq!(
+            thread.summary("app", "import"),
+            ":stop_button: **app** span `import` closed after 2.00s\nEvents: ERROR: 1, WARN: 2"
+        );
+    }
+
+    #[test]
+    fn durations_are_formatted_for_humans() {
+        assert_eq!(format_duration(Duration::from_millis(1500)), "1.50s");
+        assert_eq!(format_duration(Duration::from_secs(14 * 60 + 32)), "14m32s");
+        assert_eq!(format_duration(Duration::from_secs(3600 + 2 * 60 + 3)), "1h02m03s");
+    }
+}
@@ -0,0 +1,153 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::queue::QueueDepth;
+
+/// The stage of the layer's filtering at which an event was excluded from being sent to Discord.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterStage {
+    /// Excluded by the target filters.
+    Target,
+    /// Excluded by the message filters.
+    Message,
+    /// Excluded by the level filters.
+    Level,
+    /// Excluded by the event-by-field filters.
+    Fields,
+    /// Aggregated into the summary of an earlier occurrence of the same event.
+    Repeated,
+}
+
+/// Counters describing the delivery of events to Discord, updated by the layer, its queue and its
+/// background worker.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    enqueued: AtomicU64,
+    filtered_by_target: AtomicU64,
+    filtered_by_message: AtomicU64,
+    filtered_by_level: AtomicU64,
+    filtered_by_fields: AtomicU64,
+    repeated: AtomicU64,
+    sent: AtomicU64,
+    retried: AtomicU64,
+    dropped: AtomicU64,
+    rate_limited: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl Counters {
+    fn filtered_counter(&self, stage: FilterStage) -> &AtomicU64 {
+        match stage {
+            FilterStage::Target => &self.filtered_by_target,
+            FilterStage::Message => &self.filtered_by_message,
+            FilterStage::Level => &self.filtered_by_level,
+            FilterStage::Fields => &self.filtered_by_fields,
+            FilterStage::Repeated => &self.repeated,
+        }
+    }
+
+    pub(crate) fn record_enqueued(&self, events: usize) {
+        self.enqueued.fetch_add(events as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_filtered(&self, stage: FilterStage) {
+        self.filtered_counter(stage).fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_sent(&self, events: usize) {
+        self.sent.fetch_add(events as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_retried(&self) {
+        self.retried.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_dropped(&self, events: usize) {
+        self.dropped.fetch_add(events as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_error(&self, error: &dyn fmt::Display) {
+        *self.last_error.lock().unwrap_or_else(|e| e.into_inner()) = Some(error.to_string());
+    }
+}
+
+/// A handle to the delivery statistics of a layer and its background worker, obtained from
+/// `BackgroundWorker::stats` or `WorkerHandle::stats`.
+///
+/// All counters start at zero when the layer is built, and are only ever incremented.
+#[derive(Clone)]
+pub struct WorkerStats {
+    pub(crate) counters: Arc<Counters>,
+    pub(crate) queue: QueueDepth,
+}
+
+impl WorkerStats {
+    /// Number of events queued to be sent by the background worker.
+    pub fn enqueued(&self) -> u64 {
+        self.counters.enqueued.load(Ordering::Relaxed)
+    }
+
+    /// Number of events excluded from being sent at the given stage of the layer's filtering.
+    pub fn filtered(&self, stage: FilterStage) -> u64 {
+        self.counters.filtered_counter(stage).load(Ordering::Relaxed)
+    }
+
+    /// Number of events delivered to Discord.
+    pub fn sent(&self) -> u64 {
+        self.counters.sent.load(Ordering::Relaxed)
+    }
+
+    /// Number of requests to Discord which were retried after failing.
+    pub fn retried(&self) -> u64 {
+        self.counters.retried.load(Ordering::Relaxed)
+    }
+
+    /// Number of events which were never delivered, either discarded by the queue's overflow
+    /// policy or failing permanently.
+    pub fn dropped(&self) -> u64 {
+        self.counters.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of requests to Discord which were rejected by a rate limit.
+    pub fn rate_limited(&self) -> u64 {
+        self.counters.rate_limited.load(Ordering::Relaxed)
+    }
+
+    /// Number of events currently waiting in the queue.
+    pub fn queue_depth(&self) -> usize {
+        self.queue.events()
+    }
+
+    /// The last error which caused events not to be delivered, if any.
+    pub fn last_error(&self) -> Option<String> {
+        self.counters
+            .last_error
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+impl fmt::Debug for WorkerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WorkerStats")
+            .field("enqueued", &self.enqueued())
+            .field("filtered_by_target", &self.filtered(FilterStage::Target))
+            .field("filtered_by_message", &self.filtered(FilterStage::Message))
+            .field("filtered_by_level", &self.filtered(FilterStage::Level))
+            .field("filtered_by_fields", &self.filtered(FilterStage::Fields))
+            .field("repeated", &self.filtered(FilterStage::Repeated))
+            .field("sent", &self.sent())
+            .field("retried", &self.retried())
+            .field("dropped", &self.dropped())
+            .field("rate_limited", &self.rate_limited())
+            .field("queue_depth", &self.queue_depth())
+            .field("last_error", &self.last_error())
+            .finish()
+    }
+}
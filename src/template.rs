@@ -0,0 +1,638 @@
+use std::fmt::{self, Write};
+use std::io;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::format::{
+    discord_time, field_value, iso8601, parse_color, truncate, DiscordFormatter, EventView, MAX_FIELDS,
+    MAX_FIELD_NAME_CHARS, MAX_FIELD_VALUE_CHARS,
+};
+use crate::message::{embed_chars, PayloadMessageType, MAX_CONTENT_CHARS, MAX_TOTAL_EMBED_CHARS, OCCURRENCES_CHARS};
+
+const MAX_TITLE_CHARS: usize = 256;
+const MAX_DESCRIPTION_CHARS: usize = 4096;
+const MAX_FOOTER_CHARS: usize = 2048;
+/// Shown as the name of fields whose name renders empty, which Discord rejects.
+const BLANK_FIELD_NAME: &str = "\u{200b}";
+
+/// The templates of a [`TemplateFormatter`], e.g. loaded from a JSON config file.
+///
+/// Each template is text in which placeholders between braces are replaced by parts of the event,
+/// e.g. `{level_emoji} **{app}** `{target}`: {message}`. Braces are written `{{` and `}}`.
+///
+/// | Placeholder         | Replaced by                                                  |
+/// |---------------------|--------------------------------------------------------------|
+/// | `{app}`             | the name of the application                                  |
+/// | `{level}`           | the level of the event, e.g. `ERROR`                         |
+/// | `{level_emoji}`     | an emoji representing the level, e.g. `:x:`                  |
+/// | `{color}`           | the color of the event, as a decimal number                  |
+/// | `{message}`         | the message of the event                                     |
+/// | `{target}`          | the target of the event                                      |
+/// | `{span}`            | the spans in scope of the event, e.g. `root > request`       |
+/// | `{file}`            | the file the event was emitted from                          |
+/// | `{line}`            | the line the event was emitted from                          |
+/// | `{source}`          | the file and line, e.g. `src/main.rs#L42`                    |
+/// | `{timestamp}`       | when the event was emitted, as an ISO-8601 timestamp in UTC  |
+/// | `{timestamp:local}` | when the event was emitted, in the local time of each reader |
+/// | `{fields:json}`     | the fields of the event, as pretty-printed JSON              |
+/// | `{fields:list}`     | the fields of the event, one `key: value` line per field     |
+/// | `{field.name}`      | the value of the field `name`, or nothing if it is missing   |
+///
+/// The message is sent with the rendered `content`, along with an embed if any of the embed
+/// templates other than its color is set. Embed fields whose value renders empty are left out, those
+/// whose name renders empty are shown without a name, and the embed carries the time of the event,
+/// which Discord displays below it.
+///
+/// Each part is truncated to Discord's limits. Embeds over Discord's limit on their total size are
+/// trimmed from their last field up, then their description. A message without an embed whose
+/// content renders empty is sent with the message of the event instead.
+///
+/// ```json
+/// {
+///     "content": "{level_emoji} **{app}** `{target}`: {message}",
+///     "title": "{level} in {span}",
+///     "fields": [{ "name": "Fields", "value": "```json\n{fields:json}\n```" }],
+///     "footer": "{source}",
+///     "color": "#ff0000"
+/// }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TemplateConfig {
+    /// The text of the message.
+    pub content: Option<String>,
+    /// The title of the embed.
+    pub title: Option<String>,
+    /// The description of the embed.
+    pub description: Option<String>,
+    /// The fields of the embed, in order.
+    pub fields: Vec<TemplateField>,
+    /// The footer of the embed.
+    pub footer: Option<String>,
+    /// The color of the embed, rendered as a decimal number or a hex code, e.g. `#ff0000`. Defaults
+    /// to the color of the event.
+    pub color: Option<String>,
+}
+
+/// The templates of a field of the embed of a [`TemplateFormatter`].
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TemplateField {
+    pub name: String,
+    pub value: String,
+    /// Whether the field is displayed alongside the previous and next inline fields.
+    #[serde(default)]
+    pub inline: bool,
+}
+
+/// The error returned when templates cannot be loaded or parsed.
+#[derive(Debug)]
+pub enum TemplateError {
+    /// A template refers to an unknown placeholder, or has unbalanced braces.
+    Syntax { template: String, reason: String },
+    /// None of the templates is set, so there would be nothing to send.
+    Empty,
+    /// The config file could not be read.
+    Io(io::Error),
+    /// The config is not valid JSON, or has unknown keys.
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::Syntax { template, reason } => write!(f, "invalid template {:?}: {}", template, reason),
+            TemplateError::Empty => write!(f, "neither the content nor the embed of the message has a template"),
+            TemplateError::Io(e) => write!(f, "failed to read templates: {}", e),
+            TemplateError::Json(e) => write!(f, "invalid templates: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl From<io::Error> for TemplateError {
+    fn from(e: io::Error) -> Self {
+        TemplateError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for TemplateError {
+    fn from(e: serde_json::Error) -> Self {
+        TemplateError::Json(e)
+    }
+}
+
+/// Formats events according to templates configured at runtime, e.g. loaded from a config file,
+/// so that the layout of messages can be changed without recompiling.
+///
+/// See [`TemplateConfig`] for the placeholders available in templates.
+#[derive(Debug, Clone)]
+pub struct TemplateFormatter {
+    content: Option<Template>,
+    title: Option<Template>,
+    description: Option<Template>,
+    fields: Vec<(Template, Template, bool)>,
+    footer: Option<Template>,
+    color: Option<Template>,
+}
+
+impl TemplateFormatter {
+    /// Parse the given templates.
+    pub fn new(config: TemplateConfig) -> Result<Self, TemplateError> {
+        let parse = |template: Option<String>| template.as_deref().map(Template::parse).transpose();
+        let fields = config
+            .fields
+            .iter()
+            .map(|field| {
+                Ok((
+                    Template::parse(&field.name)?,
+                    Template::parse(&field.value)?,
+                    field.inline,
+                ))
+            })
+            .collect::<Result<Vec<_>, TemplateError>>()?;
+        let formatter = Self {
+            content: parse(config.content)?,
+            title: parse(config.title)?,
+            description: parse(config.description)?,
+            fields,
+            footer: parse(config.footer)?,
+            color: parse(config.color)?,
+        };
+        if formatter.content.is_none() && !formatter.has_embed() {
+            return Err(TemplateError::Empty);
+        }
+        Ok(formatter)
+    }
+
+    /// Parse templates from a JSON object, with the keys of [`TemplateConfig`].
+    pub fn from_json(json: &str) -> Result<Self, TemplateError> {
+        Self::new(serde_json::from_str(json)?)
+    }
+
+    /// Parse templates from a JSON file, with the keys of [`TemplateConfig`].
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, TemplateError> {
+        Self::from_json(&std::fs::read_to_string(path)?)
+    }
+
+    fn has_embed(&self) -> bool {
+        self.title.is_some() || self.description.is_some() || !self.fields.is_empty() || self.footer.is_some()
+    }
+}
+
+impl DiscordFormatter for TemplateFormatter {
+    fn format(&self, event: &EventView<'_>) -> PayloadMessageType {
+        let render = |template: &Option<Template>, max_chars: usize| {
+            template
+                .as_ref()
+                .map(|template| truncate(template.render(event), max_chars))
+                .filter(|text| !text.is_empty())
+        };
+        let content = render(&self.content, MAX_CONTENT_CHARS);
+        if !self.has_embed() {
+            // Discord rejects empty messages, fall back to the message of the event.
+            let content = content.unwrap_or_else(|| {
+                let fallback = format!("{} **{}** {}", event.emoji, event.app_name, event.message);
+                truncate(fallback, MAX_CONTENT_CHARS)
+            });
+            return PayloadMessageType::TextNoEmbed(content);
+        }
+        let mut embed = serde_json::Map::new();
+        if let Some(title) = render(&self.title, MAX_TITLE_CHARS) {
+            embed.insert("title".to_string(), title.into());
+        }
+        if let Some(description) = render(&self.description, MAX_DESCRIPTION_CHARS) {
+            embed.insert("description".to_string(), description.into());
+        }
+        let fields: Vec<Value> = self
+            .fields
+            .iter()
+            .map(|(name, value, inline)| {
+                (
+                    truncate(name.render(event), MAX_FIELD_NAME_CHARS),
+                    truncate(value.render(event), MAX_FIELD_VALUE_CHARS),
+                    inline,
+                )
+            })
+            .filter(|(_, value, _)| !value.is_empty())
+            .map(|(name, value, inline)| {
+                let name = if name.is_empty() { BLANK_FIELD_NAME.to_string() } else { name };
+                (name, value, inline)
+            })
+            // One field is kept for the number of occurrences added once the event repeats.
+            .take(MAX_FIELDS - 1)
+            .map(|(name, value, inline)| serde_json::json!({ "name": name, "value": value, "inline": inline }))
+            .collect();
+        if !fields.is_empty() {
+            embed.insert("fields".to_string(), fields.into());
+        }
+        if let Some(footer) = render(&self.footer, MAX_FOOTER_CHARS) {
+            embed.insert("footer".to_string(), serde_json::json!({ "text": footer }));
+        }
+        let color = match &self.color {
+            Some(color) => parse_color(&color.render(event)),
+            None => Some(event.color),
+        };
+        if let Some(color) = color {
+            embed.insert("color".to_string(), color.into());
+        }
+        embed.insert("timestamp".to_string(), iso8601(event.timestamp).into());
+        event.decorate(&mut embed);
+        let mut embed = Value::Object(embed);
+        fit(&mut embed);
+        let embeds = vec![embed];
+        match content {
+            Some(content) => PayloadMessageType::TextWithEmbed(content, embeds),
+            None => PayloadMessageType::EmbedNoText(embeds),
+        }
+    }
+}
+
+/// Trim an embed to Discord's limit on its total size, keeping room for the number of occurrences
+/// added once its event repeats.
+///
+/// The lowest-priority parts are trimmed first: the fields from the last one up, then the
+/// description, the footer and the title.
+fn fit(embed: &mut Value) {
+    let chars = |value: &Value| value.as_str().map_or(0, |s| s.chars().count());
+    let max_chars = MAX_TOTAL_EMBED_CHARS - OCCURRENCES_CHARS;
+    let mut excess = embed_chars(embed).saturating_sub(max_chars);
+    while excess > 0 {
+        let field = match embed["fields"].as_array_mut().and_then(|fields| fields.last_mut()) {
+            Some(field) => field,
+            None => break,
+        };
+        let value_chars = chars(&field["value"]);
+        if value_chars > excess {
+            // Trimming the value is enough, which must keep at least one character.
+            let value = field["value"].as_str().unwrap_or_default().to_string();
+            field["value"] = truncate(value, value_chars - excess).into();
+            return;
+        }
+        excess = excess.saturating_sub(value_chars + chars(&field["name"]));
+        if let Some(fields) = embed["fields"].as_array_mut() {
+            fields.pop();
+            if fields.is_empty() {
+                if let Value::Object(embed) = embed {
+                    embed.remove("fields");
+                }
+            }
+        }
+    }
+    for pointer in ["/description", "/footer/text", "/title"].iter() {
+        if excess == 0 {
+            return;
+        }
+        if let Some(Value::String(text)) = embed.pointer_mut(pointer) {
+            let text_chars = text.chars().count();
+            *text = truncate(std::mem::take(text), text_chars.saturating_sub(excess));
+            excess = excess.saturating_sub(text_chars);
+        }
+    }
+}
+
+/// A parsed template.
+#[derive(Debug, Clone)]
+struct Template(Vec<Segment>);
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+#[derive(Debug, Clone)]
+enum Placeholder {
+    App,
+    Level,
+    LevelEmoji,
+    Color,
+    Message,
+    Target,
+    Span,
+    File,
+    Line,
+    Source,
+    Timestamp,
+    LocalTimestamp,
+    FieldsJson,
+    FieldsList,
+    Field(String),
+}
+
+impl Template {
+    fn parse(template: &str) -> Result<Self, TemplateError> {
+        let error = |reason: String| TemplateError::Syntax {
+            template: template.to_string(),
+            reason,
+        };
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(error("unclosed `{`".to_string())),
+                        }
+                    }
+                    let placeholder = Placeholder::parse(&name)
+                        .ok_or_else(|| error(format!("unknown placeholder `{{{}}}`", name)))?;
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Placeholder(placeholder));
+                }
+                '}' => return Err(error("unmatched `}`, write `}}` for a literal brace".to_string())),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Self(segments))
+    }
+
+    fn render(&self, event: &EventView<'_>) -> String {
+        let mut rendered = String::new();
+        for segment in &self.0 {
+            let placeholder = match segment {
+                Segment::Literal(literal) => {
+                    rendered.push_str(literal);
+                    continue;
+                }
+                Segment::Placeholder(placeholder) => placeholder,
+            };
+            let _ = match placeholder {
+                Placeholder::App => write!(rendered, "{}", event.app_name),
+                Placeholder::Level => write!(rendered, "{}", event.level),
+                Placeholder::LevelEmoji => write!(rendered, "{}", event.emoji),
+                Placeholder::Color => write!(rendered, "{}", event.color),
+                Placeholder::Message => write!(rendered, "{}", event.message),
+                Placeholder::Target => write!(rendered, "{}", event.target),
+                Placeholder::Span => write!(rendered, "{}", event.span_chain()),
+                Placeholder::File => write!(rendered, "{}", event.source_file),
+                Placeholder::Line => write!(rendered, "{}", event.source_line),
+                Placeholder::Source => write!(rendered, "{}#L{}", event.source_file, event.source_line),
+                Placeholder::Timestamp => write!(rendered, "{}", iso8601(event.timestamp)),
+                Placeholder::LocalTimestamp => write!(rendered, "{}", discord_time(event.timestamp)),
+                Placeholder::FieldsJson => write!(rendered, "{}", event.fields_json()),
+                Placeholder::FieldsList => {
+                    let lines: Vec<String> = event
+                        .fields
+                        .iter()
+                        .map(|(key, value)| format!("{}: {}", key, field_value(value)))
+                        .collect();
+                    write!(rendered, "{}", lines.join("\n"))
+                }
+                Placeholder::Field(name) => match event.fields.get(name) {
+                    Some(value) => write!(rendered, "{}", field_value(value)),
+                    None => Ok(()),
+                },
+            };
+        }
+        rendered
+    }
+}
+
+impl Placeholder {
+    fn parse(name: &str) -> Option<Self> {
+        let placeholder = match name {
+            "app" => Placeholder::App,
+            "level" => Placeholder::Level,
+            "level_emoji" => Placeholder::LevelEmoji,
+            "color" => Placeholder::Color,
+            "message" => Placeholder::Message,
+            "target" => Placeholder::Target,
+            "span" => Placeholder::Span,
+            "file" => Placeholder::File,
+            "line" => Placeholder::Line,
+            "source" => Placeholder::Source,
+            "timestamp" => Placeholder::Timestamp,
+            "timestamp:local" => Placeholder::LocalTimestamp,
+            "fields" | "fields:json" => Placeholder::FieldsJson,
+            "fields:list" => Placeholder::FieldsList,
+            name => Placeholder::Field(name.strip_prefix("field.").filter(|name| !name.is_empty())?.to_string()),
+        };
+        Some(placeholder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::format::tests::{embed, event};
+
+    fn render(template: &str, event: &EventView<'_>) -> String {
+        Template::parse(template).unwrap().render(event)
+    }
+
+    fn syntax_error(template: &str) -> String {
+        match Template::parse(template) {
+            Err(TemplateError::Syntax { template: t, reason }) => {
+                assert_eq!(t, template);
+                reason
+            }
+            other => panic!("expected a syntax error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        let event = event("boom", json!({}));
+        assert_eq!(render("{{app}} is {app}}}", &event), "{app} is app}");
+        assert_eq!(render("{{{level}}}", &event), "{ERROR}");
+    }
+
+    #[test]
+    fn unknown_placeholders_are_rejected() {
+        assert_eq!(syntax_error("{nope}"), "unknown placeholder `{nope}`");
+        assert_eq!(syntax_error("{field.}"), "unknown placeholder `{field.}`");
+        assert_eq!(syntax_error("{}"), "unknown placeholder `{}`");
+    }
+
+    #[test]
+    fn unbalanced_braces_are_rejected() {
+        assert_eq!(syntax_error("{app"), "unclosed `{`");
+        assert_eq!(syntax_error("app}"), "unmatched `}`, write `}}` for a literal brace");
+    }
+
+    #[test]
+    fn placeholders_are_replaced_by_the_event() {
+        let event = event("boom", json!({ "user": "ada", "attempt": 3 }));
+        assert_eq!(
+            render("{level_emoji} **{app}** `{target}`: {message}", &event),
+            ":x: **app** `server::db`: boom"
+        );
+        assert_eq!(render("{span} at {source}", &event), "request > query at src/db.rs#L42");
+        assert_eq!(
+            render("{file}:{line} {level} {color}", &event),
+            "src/db.rs:42 ERROR 16711680"
+        );
+        assert_eq!(render("{timestamp}", &event), "2023-07-20T14:03:09.512Z");
+        assert_eq!(render("{timestamp:local}", &event), "<t:1689861789:F>");
+    }
+
+    #[test]
+    fn fields_are_rendered_without_json_quotes() {
+        let event = event("boom", json!({ "user": "ada", "attempt": 3 }));
+        assert_eq!(render("{field.user} ({field.attempt})", &event), "ada (3)");
+        assert_eq!(render("[{field.missing}]", &event), "[]");
+        assert_eq!(render("{fields:list}", &event), "attempt: 3\nuser: ada");
+        assert_eq!(render("{fields}", &event), render("{fields:json}", &event));
+        let json: Value = serde_json::from_str(&render("{fields:json}", &event)).unwrap();
+        assert_eq!(json, json!({ "user": "ada", "attempt": 3 }));
+    }
+
+    #[test]
+    fn config_without_templates_is_rejected() {
+        assert!(matches!(TemplateFormatter::from_json("{}"), Err(TemplateError::Empty)));
+        let color_only = TemplateFormatter::from_json(r##"{"color": "#ff0000"}"##);
+        assert!(matches!(color_only, Err(TemplateError::Empty)));
+        assert!(matches!(
+            TemplateFormatter::from_json(r#"{"contents": "{message}"}"#),
+            Err(TemplateError::Json(_))
+        ));
+        assert!(matches!(
+            TemplateFormatter::from_json(r#"{"content": "{mesage}"}"#),
+            Err(TemplateError::Syntax { .. })
+        ));
+    }
+
+    #[test]
+    fn content_only_is_sent_as_text() {
+        let formatter = TemplateFormatter::from_json(r#"{"content": "{app}: {message}"}"#).unwrap();
+        assert_eq!(
+            formatter.format(&event("boom", json!({}))),
+            PayloadMessageType::TextNoEmbed("app: boom".to_string())
+        );
+    }
+
+    #[test]
+    fn empty_content_falls_back_to_the_message() {
+        let formatter = TemplateFormatter::from_json(r#"{"content": "{field.missing}"}"#).unwrap();
+        assert_eq!(
+            formatter.format(&event("boom", json!({}))),
+            PayloadMessageType::TextNoEmbed(":x: **app** boom".to_string())
+        );
+    }
+
+    #[test]
+    fn empty_fields_are_left_out_of_embeds() {
+        let formatter = TemplateFormatter::from_json(
+            r#"{
+                "title": "{level}",
+                "fields": [
+                    { "name": "User", "value": "{field.user}", "inline": true },
+                    { "name": "Request", "value": "{field.request_id}" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let embed = embed(formatter.format(&event("boom", json!({ "user": "ada" }))));
+        assert_eq!(embed["title"], "ERROR");
+        assert_eq!(
+            embed["fields"],
+            json!([{ "name": "User", "value": "ada", "inline": true }])
+        );
+        assert_eq!(embed["color"], 0xff0000);
+        assert_eq!(embed["timestamp"], "2023-07-20T14:03:09.512Z");
+    }
+
+    #[test]
+    fn fields_whose_name_renders_empty_are_shown_without_a_name() {
+        let formatter =
+            TemplateFormatter::from_json(r#"{ "fields": [{ "name": "{field.kind}", "value": "{message}" }] }"#)
+                .unwrap();
+        let embed = embed(formatter.format(&event("boom", json!({}))));
+        assert_eq!(
+            embed["fields"],
+            json!([{ "name": "\u{200b}", "value": "boom", "inline": false }])
+        );
+    }
+
+    #[test]
+    fn invalid_colors_are_left_out() {
+        let color = |color: &str| {
+            let config = TemplateConfig {
+                title: Some("{level}".to_string()),
+                color: Some(color.to_string()),
+                ..TemplateConfig::default()
+            };
+            let formatter = TemplateFormatter::new(config).unwrap();
+            embed(formatter.format(&event("boom", json!({}))))["color"].clone()
+        };
+        assert_eq!(color("#00ff00"), 0x00ff00);
+        assert_eq!(color("0x0000ff"), 0x0000ff);
+        assert_eq!(color("255"), 255);
+        assert_eq!(color("#1000000"), Value::Null);
+        assert_eq!(color("green"), Value::Null);
+    }
+
+    #[test]
+    fn parts_are_truncated_to_discord_limits() {
+        let config = TemplateConfig {
+            title: Some("{message}".to_string()),
+            fields: (0..30)
+                .map(|i| TemplateField {
+                    name: format!("{}", i),
+                    value: "{level}".to_string(),
+                    inline: true,
+                })
+                .collect(),
+            ..TemplateConfig::default()
+        };
+        let formatter = TemplateFormatter::new(config).unwrap();
+        let message = "m".repeat(1000);
+        let embed = embed(formatter.format(&event(&message, json!({}))));
+        let title = embed["title"].as_str().unwrap();
+        assert_eq!(title.chars().count(), MAX_TITLE_CHARS);
+        assert!(title.ends_with('…'));
+        assert_eq!(embed["fields"].as_array().unwrap().len(), MAX_FIELDS - 1);
+    }
+
+    #[test]
+    fn embeds_are_trimmed_from_their_last_field_up() {
+        let field = |name: &str| TemplateField {
+            name: name.to_string(),
+            value: "{fields:json}".to_string(),
+            inline: false,
+        };
+        let config = TemplateConfig {
+            description: Some("{message}".to_string()),
+            fields: vec![field("a"), field("b"), field("c"), field("d")],
+            ..TemplateConfig::default()
+        };
+        let formatter = TemplateFormatter::new(config).unwrap();
+        let message = "m".repeat(3000);
+        let value = "v".repeat(2000);
+        let embed = embed(formatter.format(&event(&message, json!({ "value": value }))));
+        assert!(embed_chars(&embed) <= MAX_TOTAL_EMBED_CHARS - OCCURRENCES_CHARS);
+        assert_eq!(embed["description"].as_str().unwrap().chars().count(), 3000);
+        let fields = embed["fields"].as_array().unwrap();
+        let names: Vec<&str> = fields.iter().map(|field| field["name"].as_str().unwrap()).collect();
+        assert_eq!(names, vec!["a", "b", "c"]);
+        assert_eq!(
+            fields[0]["value"].as_str().unwrap().chars().count(),
+            MAX_FIELD_VALUE_CHARS
+        );
+        assert!(fields[2]["value"].as_str().unwrap().ends_with('…'));
+    }
+}
@@ -0,0 +1,57 @@
+//! Fixtures shared by the unit tests of several modules.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::message::{MessagePayload, PayloadMessageType};
+
+pub(crate) const WEBHOOK: &str = "https://discord.com/api/webhooks/1/token";
+
+/// A file in the temporary directory, removed once dropped along with the files named after it,
+/// e.g. `<name>.replaying`.
+pub(crate) struct TempFile(PathBuf);
+
+impl TempFile {
+    /// A path unique to this test run, which does not exist yet.
+    pub(crate) fn new(kind: &str) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "tracing-layer-discord-{}-{}-{}.jsonl",
+            kind,
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        );
+        Self(std::env::temp_dir().join(name))
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let name = self.0.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let siblings = fs::read_dir(std::env::temp_dir()).into_iter().flatten().flatten();
+        for entry in siblings.filter(|entry| entry.file_name().to_string_lossy().starts_with(&name)) {
+            let _ = fs::remove_file(entry.path()).or_else(|_| fs::remove_dir(entry.path()));
+        }
+    }
+}
+
+/// A text message to [`WEBHOOK`].
+pub(crate) fn payload(text: &str) -> MessagePayload {
+    MessagePayload::new(PayloadMessageType::TextNoEmbed(text.to_string()), WEBHOOK.to_string())
+}
+
+/// The text of a message.
+pub(crate) fn content(payload: &MessagePayload) -> String {
+    let payload = serde_json::to_value(payload).unwrap();
+    payload["content"].as_str().unwrap().to_string()
+}
+
+/// The text of each message.
+pub(crate) fn contents(payloads: &[MessagePayload]) -> Vec<String> {
+    payloads.iter().map(content).collect()
+}
@@ -0,0 +1,298 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use serde::{Deserialize, Serialize};
+
+use crate::message::{MessagePayload, StoredPayload};
+
+/// Number of acknowledged entries after which the log is compacted, if it still holds pending ones.
+const COMPACT_AFTER_ACKS: usize = 1024;
+
+/// An append-only file of JSON lines, logging the payloads queued for the background worker until
+/// they have been processed, so that the payloads queued before a crash are sent by the next
+/// instance of the process.
+///
+/// Entries are written to the file as soon as they are queued, which makes them survive the process
+/// crashing, but not the machine: the file is not synced to disk on every write.
+#[derive(Debug)]
+pub(crate) struct WriteAheadLog {
+    path: PathBuf,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    file: File,
+    next_sequence: u64,
+    /// The lines of the entries which were not acknowledged yet, by sequence number.
+    pending: BTreeMap<u64, String>,
+    /// Number of entries acknowledged since the log was last compacted.
+    acked: usize,
+}
+
+/// A line of the log.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    /// A payload was queued.
+    Payload {
+        sequence: u64,
+        #[serde(flatten)]
+        payload: StoredPayload,
+    },
+    /// A payload was processed, whether it was delivered or not.
+    Ack { ack: u64 },
+}
+
+impl WriteAheadLog {
+    /// Open the log, creating its file if needed.
+    ///
+    /// Returns the payloads logged by a previous instance of the process which were never
+    /// processed, to be queued again. Lines which cannot be parsed, e.g. truncated by a crash while
+    /// being written, are skipped.
+    pub(crate) fn open(path: PathBuf) -> io::Result<(Self, Vec<MessagePayload>)> {
+        let mut pending = BTreeMap::new();
+        let mut next_sequence = 0;
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str::<Entry>(&line) {
+                        Ok(Entry::Payload { sequence, .. }) => {
+                            next_sequence = next_sequence.max(sequence + 1);
+                            pending.insert(sequence, line);
+                        }
+                        Ok(Entry::Ack { ack }) => {
+                            pending.remove(&ack);
+                        }
+                        Err(e) => tracing::warn!(err = %e, "skipping malformed line of the discord write-ahead log"),
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let mut recovered = Vec::new();
+        for line in pending.values() {
+            if let Ok(Entry::Payload { sequence, payload }) = serde_json::from_str::<Entry>(line) {
+                match payload.into_payload() {
+                    Ok(payload) => recovered.push(payload.with_sequence(sequence)),
+                    Err(e) => tracing::warn!(err = %e, "skipping malformed payload of the discord write-ahead log"),
+                }
+            }
+        }
+        let file = Self::rewrite(&path, &pending)?;
+        let state = State {
+            file,
+            next_sequence,
+            pending,
+            acked: 0,
+        };
+        let log = Self {
+            path,
+            state: Mutex::new(state),
+        };
+        Ok((log, recovered))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Log a payload, returning the sequence number of its entry.
+    pub(crate) fn append(&self, payload: &MessagePayload) -> io::Result<u64> {
+        let mut state = self.lock();
+        let sequence = state.next_sequence;
+        let entry = Entry::Payload {
+            sequence,
+            payload: StoredPayload::new(payload)?,
+        };
+        let line = serde_json::to_string(&entry)?;
+        Self::write_line(&mut state.file, &line)?;
+        state.next_sequence += 1;
+        state.pending.insert(sequence, line);
+        Ok(sequence)
+    }
+
+    /// Acknowledge that the payloads logged under the given sequence numbers were processed, so
+    /// that they are not queued again by the next instance of the process.
+    pub(crate) fn ack(&self, sequences: &[u64]) -> io::Result<()> {
+        if sequences.is_empty() {
+            return Ok(());
+        }
+        let mut state = self.lock();
+        for sequence in sequences {
+            if state.pending.remove(sequence).is_some() {
+                let line = serde_json::to_string(&Entry::Ack { ack: *sequence })?;
+                Self::write_line(&mut state.file, &line)?;
+                state.acked += 1;
+            }
+        }
+        if state.pending.is_empty() {
+            state.file.set_len(0)?;
+            state.acked = 0;
+        } else if state.acked >= COMPACT_AFTER_ACKS && state.acked >= state.pending.len() {
+            state.file = Self::rewrite(&self.path, &state.pending)?;
+            state.acked = 0;
+        }
+        Ok(())
+    }
+
+    /// Replace the file with one holding only the given entries, and open it for appending.
+    fn rewrite(path: &Path, pending: &BTreeMap<u64, String>) -> io::Result<File> {
+        let mut name = path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+        name.push(".compacting");
+        let compacting = path.with_file_name(name);
+        let mut file = File::create(&compacting)?;
+        for line in pending.values() {
+            Self::write_line(&mut file, line)?;
+        }
+        file.sync_all()?;
+        fs::rename(&compacting, path)?;
+        OpenOptions::new().append(true).open(path)
+    }
+
+    fn write_line(file: &mut File, line: &str) -> io::Result<()> {
+        // A single write, so that a crash never leaves more than the last line truncated.
+        let mut buffer = Vec::with_capacity(line.len() + 1);
+        buffer.extend_from_slice(line.as_bytes());
+        buffer.push(b'\n');
+        file.write_all(&buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{content, payload, TempFile, WEBHOOK};
+
+    fn open(log: &TempFile) -> (WriteAheadLog, Vec<MessagePayload>) {
+        WriteAheadLog::open(log.path().to_path_buf()).unwrap()
+    }
+
+    fn lines(log: &TempFile) -> Vec<String> {
+        fs::read_to_string(log.path())
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn missing_file_recovers_nothing() {
+        let temp = TempFile::new("wal");
+        let (_log, recovered) = open(&temp);
+        assert!(recovered.is_empty());
+        assert!(temp.path().exists());
+    }
+
+    #[test]
+    fn unacknowledged_payloads_are_recovered_in_order() {
+        let temp = TempFile::new("wal");
+        let (log, _) = open(&temp);
+        assert_eq!(log.append(&payload("first")).unwrap(), 0);
+        assert_eq!(log.append(&payload("second")).unwrap(), 1);
+        assert_eq!(log.append(&payload("third")).unwrap(), 2);
+        log.ack(&[1]).unwrap();
+        drop(log);
+
+        let (log, recovered) = open(&temp);
+        let recovered: Vec<(String, Vec<u64>)> = recovered
+            .iter()
+            .map(|payload| (content(payload), payload.sequences().to_vec()))
+            .collect();
+        assert_eq!(
+            recovered,
+            vec![("first".to_string(), vec![0]), ("third".to_string(), vec![2])]
+        );
+        // Sequence numbers keep increasing past the recovered entries.
+        assert_eq!(log.append(&payload("fourth")).unwrap(), 3);
+    }
+
+    #[test]
+    fn recovered_payloads_keep_their_webhook() {
+        let temp = TempFile::new("wal");
+        let (log, _) = open(&temp);
+        log.append(&payload("event")).unwrap();
+        drop(log);
+
+        let (_log, recovered) = open(&temp);
+        assert_eq!(recovered[0].webhook_url(), WEBHOOK);
+        assert_eq!(recovered[0].events(), 1);
+    }
+
+    #[test]
+    fn truncated_last_line_is_skipped() {
+        let temp = TempFile::new("wal");
+        let (log, _) = open(&temp);
+        log.append(&payload("first")).unwrap();
+        log.append(&payload("second")).unwrap();
+        drop(log);
+        // A crash while writing the third entry left only part of its line.
+        let mut file = OpenOptions::new().append(true).open(temp.path()).unwrap();
+        file.write_all(br#"{"sequence":2,"webhook_url":"https://disc"#).unwrap();
+        drop(file);
+
+        let (log, recovered) = open(&temp);
+        let contents: Vec<String> = recovered.iter().map(content).collect();
+        assert_eq!(contents, vec!["first", "second"]);
+        // The file is rewritten without the truncated line, so that later entries are readable.
+        assert_eq!(lines(&temp).len(), 2);
+        log.append(&payload("third")).unwrap();
+        drop(log);
+        let (_log, recovered) = open(&temp);
+        assert_eq!(recovered.len(), 3);
+    }
+
+    #[test]
+    fn acknowledging_every_payload_empties_the_file() {
+        let temp = TempFile::new("wal");
+        let (log, _) = open(&temp);
+        let first = log.append(&payload("first")).unwrap();
+        let second = log.append(&payload("second")).unwrap();
+        log.ack(&[first]).unwrap();
+        assert_eq!(lines(&temp).len(), 3);
+        log.ack(&[second]).unwrap();
+        assert!(lines(&temp).is_empty());
+        drop(log);
+
+        let (_log, recovered) = open(&temp);
+        assert!(recovered.is_empty());
+    }
+
+    #[test]
+    fn unknown_and_repeated_acks_are_ignored() {
+        let temp = TempFile::new("wal");
+        let (log, _) = open(&temp);
+        let first = log.append(&payload("first")).unwrap();
+        log.append(&payload("second")).unwrap();
+        log.ack(&[first]).unwrap();
+        log.ack(&[first, 42]).unwrap();
+        log.ack(&[]).unwrap();
+        assert_eq!(lines(&temp).len(), 3);
+    }
+
+    #[test]
+    fn log_is_compacted_once_enough_entries_are_acknowledged() {
+        let temp = TempFile::new("wal");
+        let (log, _) = open(&temp);
+        let kept = log.append(&payload("kept")).unwrap();
+        let sequences: Vec<u64> = (0..COMPACT_AFTER_ACKS)
+            .map(|i| log.append(&payload(&i.to_string())).unwrap())
+            .collect();
+        log.ack(&sequences).unwrap();
+        assert_eq!(lines(&temp).len(), 1);
+        drop(log);
+
+        let (_log, recovered) = open(&temp);
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(content(&recovered[0]), "kept");
+        assert_eq!(recovered[0].sequences(), &[kept]);
+    }
+}
@@ -1,50 +1,508 @@
-use crate::message::MessagePayload;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::aggregate::{Aggregator, Fingerprint, Repeats};
+use crate::dead_letter::DeadLetterSpool;
+use crate::message::{Identity, MessagePayload, Thread, MAX_EMBEDS};
+use crate::queue::OverflowPolicy;
+use crate::rate_limit::RateLimiter;
+use crate::stats::{Counters, WorkerStats};
 use crate::{ChannelReceiver, ChannelSender};
+use reqwest::{Method, StatusCode, Url};
+use serde::Deserialize;
+use tokio::sync::{oneshot, watch};
 use tokio::task::JoinHandle;
+use tokio::time::Instant;
 
 /// Maximum number of retries for failed requests
 const MAX_RETRIES: usize = 10;
 
+/// Configuration of the background worker, and of the queue of payloads waiting to be sent by it.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct WorkerConfig {
+    /// Maximum number of payloads waiting to be sent, if bounded.
+    pub(crate) capacity: Option<usize>,
+    /// What to do with new events once the queue is full.
+    pub(crate) overflow_policy: OverflowPolicy,
+    /// How long to wait for further payloads to batch into a single request, if batching.
+    pub(crate) batch_linger: Option<Duration>,
+    /// Where to write the payloads which exhausted their retries, if anywhere.
+    pub(crate) dead_letter: Option<DeadLetterSpool>,
+    /// Where to log the queued payloads until they are processed, if the queue is durable.
+    pub(crate) write_ahead_log: Option<PathBuf>,
+    /// The name and avatar of the webhooks in the messages they post.
+    pub(crate) identity: Identity,
+}
+
 /// Provides a background worker task that sends the messages generated by the
 /// layer.
-pub(crate) async fn worker(mut rx: ChannelReceiver) {
-    let client = reqwest::Client::new();
-    while let Some(message) = rx.recv().await {
-        match message {
-            WorkerMessage::Data(payload) => {
-                let webhook_url = payload.webhook_url().to_string();
-                let payload =
-                    serde_json::to_string(&payload).expect("failed to deserialize discord payload, this is a bug");
-
-                let mut retries = 0;
-                while retries < MAX_RETRIES {
-                    match client
-                        .post(webhook_url.clone())
-                        .header("Content-Type", "application/json")
-                        .body(payload.clone())
-                        .send()
-                        .await
-                    {
-                        Ok(res) => {
-                            let res_text = res.text().await.unwrap();
-                            break; // Success, break out of the retry loop
-                        }
-                        Err(e) => {}
-                    };
-
-                    // Exponential backoff - increase the delay between retries
-                    let delay_ms = 2u64.pow(retries as u32) * 100;
-                    tokio::time::sleep(std::time::Duration::from_millis(delay_ms)).await;
-                    retries += 1;
+///
+/// The worker stops once it receives `WorkerMessage::Shutdown`, or as soon as the shutdown deadline
+/// elapses, and reports what happened to the payloads it received.
+pub(crate) async fn worker(
+    mut rx: ChannelReceiver,
+    config: WorkerConfig,
+    aggregator: Option<Arc<Aggregator>>,
+    deadline: watch::Receiver<Option<Instant>>,
+    counters: Arc<Counters>,
+) -> ShutdownReport {
+    let mut worker = Worker::new(deadline, counters.clone(), config.identity.clone());
+    let mut report = ShutdownReport::default();
+    // Messages to process before receiving more from the queue, e.g. a message received while
+    // batching which could not be merged into the batch.
+    let mut pending = VecDeque::new();
+    // Whether the aggregator was drained for the flush or shutdown at the front of the pending
+    // messages, which is not drained again once its repeats were reported.
+    let mut drained = false;
+    loop {
+        let message = match pending.pop_front() {
+            Some(message) => message,
+            None => match worker
+                .before_deadline(recv(&mut rx, aggregator.as_deref(), &mut pending))
+                .await
+            {
+                Ok(Some(message)) => message,
+                Ok(None) | Err(_) => break,
+            },
+        };
+        if matches!(message, WorkerMessage::Flush(_) | WorkerMessage::Shutdown) && !std::mem::take(&mut drained) {
+            // Report the repeats counted so far before acknowledging the flush or stopping. Events
+            // repeating in the meantime are left to their window, so that a flush completes even
+            // while they keep repeating.
+            let repeats = aggregator.as_deref().map(Aggregator::drain).unwrap_or_default();
+            if !repeats.is_empty() {
+                drained = true;
+                pending.push_front(message);
+                for repeats in repeats.into_iter().rev() {
+                    pending.push_front(WorkerMessage::Repeats(repeats));
+                }
+                continue;
+            }
+        }
+        // A copy of the payload to write to the dead-letter spool, should it exhaust its retries.
+        // The sequence numbers of the payload in the write-ahead log, acknowledged once processed.
+        let mut sequences = Vec::new();
+        let (events, dead_letter, result) = match message {
+            WorkerMessage::Data(mut payload) => {
+                if let Some(linger) = config.batch_linger {
+                    pending.extend(batch(&mut rx, &mut payload, linger).await);
                 }
+                sequences.extend_from_slice(payload.sequences());
+                let dead_letter = config.dead_letter.as_ref().map(|_| payload.clone());
+                (payload.events(), dead_letter, worker.post(payload).await)
+            }
+            WorkerMessage::Repeats(repeats) => {
+                let dead_letter = config.dead_letter.as_ref().map(|_| repeats.summary.clone());
+                (1, dead_letter, worker.report_repeats(repeats).await)
+            }
+            WorkerMessage::Flush(ack) => {
+                // Everything queued before the flush was processed, the caller may no longer be
+                // waiting for it.
+                let _ = ack.send(());
+                continue;
+            }
+            WorkerMessage::CloseThread(key) => {
+                worker.close_thread(key);
+                continue;
+            }
+            WorkerMessage::Forget(fingerprint) => {
+                worker.posted.remove(&fingerprint);
+                continue;
             }
             WorkerMessage::Shutdown => {
                 break;
             }
+        };
+        match result {
+            Ok(()) => {
+                report.delivered += events;
+                counters.record_sent(events);
+            }
+            // Left unacknowledged in the write-ahead log, to be sent by the next instance of the
+            // process.
+            Err(DeliveryError::DeadlineElapsed) => {
+                report.pending += events;
+                break;
+            }
+            Err(e) => {
+                report.dropped += events;
+                counters.record_dropped(events);
+                counters.record_error(&e);
+                tracing::error!(err = %e, "failed to deliver discord payload");
+                if let (DeliveryError::RetriesExhausted(_), Some(spool), Some(payload)) =
+                    (&e, &config.dead_letter, dead_letter)
+                {
+                    if let Err(e) = spool.append(&payload) {
+                        tracing::error!(
+                            err = %e,
+                            path = %spool.path().display(),
+                            "failed to write discord payload to the dead-letter spool"
+                        );
+                    }
+                }
+            }
+        }
+        rx.ack(&sequences);
+    }
+    report.pending += pending.iter().map(WorkerMessage::events).sum::<usize>();
+    // Repeats still counted when the deadline elapsed were never reported.
+    report.pending += aggregator.as_deref().map_or(0, |aggregator| aggregator.drain().len());
+    report
+}
+
+/// Receive the next message from the queue.
+///
+/// While waiting, the repeats of aggregated events are queued as pending once their window elapses.
+async fn recv(
+    rx: &mut ChannelReceiver,
+    aggregator: Option<&Aggregator>,
+    pending: &mut VecDeque<WorkerMessage>,
+) -> Option<WorkerMessage> {
+    let aggregator = match aggregator {
+        Some(aggregator) => aggregator,
+        None => return rx.recv().await,
+    };
+    loop {
+        let expiry = aggregator.next_expiry();
+        let elapsed = async {
+            match expiry {
+                Some(expiry) => tokio::time::sleep_until(expiry).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            message = rx.recv() => return message,
+            // A window opened, which may elapse before the one waited for, if any.
+            _ = aggregator.opened() => {}
+            _ = elapsed => {
+                let (repeats, forgotten) = aggregator.expire();
+                pending.extend(repeats.into_iter().map(WorkerMessage::Repeats));
+                pending.extend(forgotten.into_iter().map(WorkerMessage::Forget));
+                if let Some(message) = pending.pop_front() {
+                    return Some(message);
+                }
+            }
         }
     }
 }
 
+/// Resolve once the shutdown deadline has elapsed. Never resolves if no deadline is ever set.
+async fn deadline_elapsed(mut deadline: watch::Receiver<Option<Instant>>) {
+    loop {
+        let current = *deadline.borrow_and_update();
+        match current {
+            Some(deadline) => return tokio::time::sleep_until(deadline).await,
+            None => {
+                if deadline.changed().await.is_err() {
+                    return std::future::pending().await;
+                }
+            }
+        }
+    }
+}
+
+/// Merge the payloads received within the linger time into the given payload, until it holds as
+/// many embeds as Discord accepts in a single message.
+///
+/// Returns the first message which could not be merged, to be processed next.
+async fn batch(rx: &mut ChannelReceiver, payload: &mut MessagePayload, linger: Duration) -> Option<WorkerMessage> {
+    let deadline = Instant::now() + linger;
+    while (1..MAX_EMBEDS).contains(&payload.embed_count()) {
+        match tokio::time::timeout_at(deadline, rx.recv()).await {
+            Ok(Some(WorkerMessage::Data(next))) => {
+                if let Err(next) = payload.merge(next) {
+                    return Some(WorkerMessage::Data(*next));
+                }
+            }
+            Ok(Some(message)) => return Some(message),
+            Ok(None) | Err(_) => return None,
+        }
+    }
+    None
+}
+
+/// Sends the requests to the Discord webhooks, keeping track of the state required to do so.
+struct Worker {
+    client: reqwest::Client,
+    rate_limiter: RateLimiter,
+    /// The instant by which the worker must stop, once shutting down with a timeout.
+    deadline: watch::Receiver<Option<Instant>>,
+    /// Messages posted for the first occurrence of events, to be edited in place once they repeat,
+    /// until a whole aggregation window elapses without repeats.
+    posted: HashMap<Fingerprint, PostedMessage>,
+    /// Threads created for forum posts, by the fingerprint of the kind of event they were created for.
+    threads: HashMap<Fingerprint, String>,
+    counters: Arc<Counters>,
+    identity: Identity,
+}
+
+/// A message posted to a webhook, along with the payload it was created from.
+struct PostedMessage {
+    id: String,
+    /// The thread the message was posted in, if any.
+    thread_id: Option<String>,
+    payload: MessagePayload,
+}
+
+/// The message created by a webhook, as returned when posting with `?wait=true`.
+#[derive(Debug, Deserialize)]
+struct WebhookMessage {
+    id: String,
+    /// The channel the message was posted in, which is the thread created for a new forum post.
+    channel_id: String,
+}
+
+impl Worker {
+    fn new(deadline: watch::Receiver<Option<Instant>>, counters: Arc<Counters>, identity: Identity) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            rate_limiter: RateLimiter::default(),
+            deadline,
+            posted: HashMap::new(),
+            threads: HashMap::new(),
+            counters,
+            identity,
+        }
+    }
+
+    /// Post a payload to its webhook.
+    ///
+    /// If the payload is to be edited in place once its event repeats, Discord is asked to return
+    /// the created message, whose ID is kept to edit it later. Likewise, the ID of the thread created
+    /// by the first payload of a forum post is kept to post the later ones into it.
+    async fn post(&mut self, mut payload: MessagePayload) -> Result<(), DeliveryError> {
+        let fingerprint = payload.fingerprint();
+        loop {
+            let (thread_id, forum_post) = match payload.thread() {
+                Thread::Channel => (None, None),
+                Thread::Existing(id) => (Some(id.clone()), None),
+                Thread::ForumPost { key, name } => match self.threads.get(key) {
+                    Some(id) => (Some(id.clone()), None),
+                    None => (None, Some((*key, name.clone()))),
+                },
+            };
+            payload.set_thread_name(forum_post.as_ref().map(|(_, name)| name.clone()));
+            payload.set_identity(Some(&self.identity));
+            let mut url = parse_url(payload.webhook_url())?;
+            if let Some(thread_id) = &thread_id {
+                url.query_pairs_mut().append_pair("thread_id", thread_id);
+            }
+            if fingerprint.is_some() || forum_post.is_some() {
+                url.query_pairs_mut().append_pair("wait", "true");
+            }
+            let response = match self.deliver(Method::POST, url, &payload).await {
+                Ok(response) => response,
+                Err(e) => {
+                    // The thread of the forum post was deleted, create a new one.
+                    if let (true, Thread::ForumPost { key, .. }) = (e.is_not_found(), payload.thread()) {
+                        if self.threads.remove(key).is_some() {
+                            continue;
+                        }
+                    }
+                    return Err(e);
+                }
+            };
+            // The message was delivered, failing to keep track of it only means it cannot be edited,
+            // or that the next event of its kind creates another forum post.
+            let message = serde_json::from_str::<WebhookMessage>(&response).ok();
+            let thread_id = match (forum_post, &message) {
+                (Some((key, _)), Some(message)) => {
+                    self.threads.insert(key, message.channel_id.clone());
+                    Some(message.channel_id.clone())
+                }
+                _ => thread_id,
+            };
+            if let (Some(fingerprint), Some(message)) = (fingerprint, message) {
+                payload.set_thread_name(None);
+                payload.set_identity(None);
+                let posted = PostedMessage {
+                    id: message.id,
+                    thread_id,
+                    payload,
+                };
+                self.posted.insert(fingerprint, posted);
+            }
+            return Ok(());
+        }
+    }
+
+    /// Forget the thread created for a forum post and the messages posted in it, which are no longer
+    /// posted into or edited once the span it was opened for closed.
+    fn close_thread(&mut self, key: Fingerprint) {
+        self.threads.remove(&key);
+        self.posted
+            .retain(|_, posted| !matches!(posted.payload.thread(), Thread::ForumPost { key: k, .. } if *k == key));
+    }
+
+    /// Report the repeats of an event, by editing the occurrence count of the message posted for
+    /// it, or by posting a summary if there is no such message.
+    async fn report_repeats(&mut self, repeats: Repeats) -> Result<(), DeliveryError> {
+        let posted = match self.posted.get(&repeats.fingerprint) {
+            Some(posted) => posted,
+            None => return self.post(repeats.summary).await,
+        };
+        let mut url = parse_url(posted.payload.webhook_url())?;
+        url.path_segments_mut()
+            .map_err(|_| DeliveryError::InvalidUrl("webhook url cannot be a base".to_string()))?
+            .extend(&["messages", posted.id.as_str()]);
+        if let Some(thread_id) = &posted.thread_id {
+            url.query_pairs_mut().append_pair("thread_id", thread_id);
+        }
+        let edited = posted.payload.with_occurrences(repeats.occurrences, repeats.last_seen);
+        match self.deliver(Method::PATCH, url, &edited).await {
+            Ok(_) => Ok(()),
+            Err(e) if e.is_not_found() => {
+                // The message was deleted, fall back to posting a summary.
+                self.posted.remove(&repeats.fingerprint);
+                self.post(repeats.summary).await
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Run a future, giving up once the shutdown deadline elapses.
+    async fn before_deadline<F: Future>(&self, future: F) -> Result<F::Output, DeliveryError> {
+        tokio::select! {
+            output = future => Ok(output),
+            _ = deadline_elapsed(self.deadline.clone()) => Err(DeliveryError::DeadlineElapsed),
+        }
+    }
+
+    /// Send a payload to its webhook, and return the body of the response.
+    async fn deliver(&mut self, method: Method, url: Url, payload: &MessagePayload) -> Result<String, DeliveryError> {
+        let deadline = self.deadline.clone();
+        tokio::select! {
+            result = self.deliver_with_retries(method, url, payload) => result,
+            _ = deadline_elapsed(deadline) => Err(DeliveryError::DeadlineElapsed),
+        }
+    }
+
+    /// Send a payload to its webhook, retrying transient failures with an exponential backoff.
+    ///
+    /// Rate-limited requests are held back until the webhook's bucket resets rather than backing
+    /// off, while permanent failures (e.g. a malformed embed or a deleted webhook) are not retried at
+    /// all.
+    async fn deliver_with_retries(
+        &mut self,
+        method: Method,
+        url: Url,
+        payload: &MessagePayload,
+    ) -> Result<String, DeliveryError> {
+        let webhook_url = payload.webhook_url();
+        let body = serde_json::to_string(payload).expect("failed to deserialize discord payload, this is a bug");
+
+        let mut retries = 0;
+        loop {
+            self.rate_limiter.acquire(webhook_url).await;
+            let error = match self
+                .client
+                .request(method.clone(), url.clone())
+                .header("Content-Type", "application/json")
+                .body(body.clone())
+                .send()
+                .await
+            {
+                Ok(res) => {
+                    self.rate_limiter.update(webhook_url, res.headers());
+                    let status = res.status();
+                    let headers = res.headers().clone();
+                    let body = res.text().await.unwrap_or_default();
+                    if status.is_success() {
+                        return Ok(body);
+                    }
+                    if status == StatusCode::TOO_MANY_REQUESTS {
+                        self.counters.record_rate_limited();
+                        self.rate_limiter.limited(webhook_url, &headers, &body);
+                    }
+                    DeliveryError::Status { status, body }
+                }
+                Err(e) => DeliveryError::Transport(e.without_url()),
+            };
+
+            if !error.is_retryable() {
+                return Err(error);
+            }
+            retries += 1;
+            if retries >= MAX_RETRIES {
+                return Err(DeliveryError::RetriesExhausted(Box::new(error)));
+            }
+            self.counters.record_retried();
+            if !error.is_rate_limited() {
+                // Exponential backoff - increase the delay between retries
+                let delay_ms = 2u64.pow(retries as u32 - 1) * 100;
+                tokio::time::sleep(std::time::Duration::from_millis(delay_ms)).await;
+            }
+        }
+    }
+}
+
+/// Parse the URL of a webhook.
+fn parse_url(webhook_url: &str) -> Result<Url, DeliveryError> {
+    Url::parse(webhook_url).map_err(|e| DeliveryError::InvalidUrl(e.to_string()))
+}
+
+/// The reason a payload could not be delivered to Discord.
+#[derive(Debug)]
+pub(crate) enum DeliveryError {
+    /// The webhook URL is malformed.
+    InvalidUrl(String),
+    /// The request could not be sent, or no response was received.
+    Transport(reqwest::Error),
+    /// Discord responded with a non-2xx status code.
+    Status { status: StatusCode, body: String },
+    /// The payload was still failing after the maximum number of retries.
+    RetriesExhausted(Box<DeliveryError>),
+    /// The worker was shut down before the payload could be delivered.
+    DeadlineElapsed,
+}
+
+impl DeliveryError {
+    /// Whether the failure is transient, and the request may succeed if sent again.
+    ///
+    /// Transport errors, rate limits and server errors are retryable. Any other status (e.g. 400
+    /// for a malformed embed, or 401/404 for a deleted webhook) is permanent.
+    pub(crate) fn is_retryable(&self) -> bool {
+        match self {
+            DeliveryError::InvalidUrl(_) => false,
+            DeliveryError::Transport(_) => true,
+            DeliveryError::Status { status, .. } => {
+                *status == StatusCode::TOO_MANY_REQUESTS
+                    || *status == StatusCode::REQUEST_TIMEOUT
+                    || status.is_server_error()
+            }
+            DeliveryError::RetriesExhausted(_) | DeliveryError::DeadlineElapsed => false,
+        }
+    }
+
+    /// Whether the failure was caused by Discord rate limiting the request.
+    pub(crate) fn is_rate_limited(&self) -> bool {
+        matches!(self, DeliveryError::Status { status, .. } if *status == StatusCode::TOO_MANY_REQUESTS)
+    }
+
+    /// Whether the webhook or message the request was sent to does not exist.
+    pub(crate) fn is_not_found(&self) -> bool {
+        matches!(self, DeliveryError::Status { status, .. } if *status == StatusCode::NOT_FOUND)
+    }
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeliveryError::InvalidUrl(e) => write!(f, "invalid webhook url: {}", e),
+            DeliveryError::Transport(e) => write!(f, "request to discord failed: {}", e),
+            DeliveryError::Status { status, body } => write!(f, "discord responded with {}: {}", status, body),
+            DeliveryError::RetriesExhausted(e) => write!(f, "gave up after {} attempts: {}", MAX_RETRIES, e),
+            DeliveryError::DeadlineElapsed => write!(f, "shutdown deadline elapsed"),
+        }
+    }
+}
+
+impl std::error::Error for DeliveryError {}
+
 /// This worker manages a background async task that schedules the network requests to send traces
 /// to the Discord on the running tokio runtime.
 ///
@@ -53,10 +511,15 @@ pub(crate) async fn worker(mut rx: ChannelReceiver) {
 ///
 /// `tracing-layer-discord` synchronously generates payloads to send to the Discord API using the
 /// tracing events from the global subscriber. However, all network requests are offloaded onto
-/// an unbuffered channel and processed by a provided future acting as an asynchronous worker.
+/// a queue (unbounded, unless configured otherwise) and processed by a provided future acting as an
+/// asynchronous worker.
 pub struct BackgroundWorker {
     pub(crate) sender: ChannelSender,
-    pub(crate) handle: JoinHandle<()>,
+    pub(crate) join_handle: JoinHandle<ShutdownReport>,
+    /// Sets the instant by which the worker must stop, once shutting down with a timeout.
+    pub(crate) deadline: watch::Sender<Option<Instant>>,
+    pub(crate) stats: WorkerStats,
+    pub(crate) dead_letter: Option<DeadLetterSpool>,
 }
 
 impl BackgroundWorker {
@@ -65,13 +528,505 @@ impl BackgroundWorker {
     /// Without invoking`.teardown()`, your application may exit before all Discord messages can be
     /// sent.
     pub async fn shutdown(self) {
-        self.sender.send(WorkerMessage::Shutdown).unwrap();
-        self.handle.await.unwrap();
+        self.stop().await;
     }
+
+    /// Wait until every payload queued before this call has been delivered, or has permanently
+    /// failed, without shutting down the worker.
+    ///
+    /// The repeats of aggregated events counted so far are reported too, without waiting for their
+    /// window to elapse.
+    pub async fn flush(&self) {
+        flush(&self.sender).await
+    }
+
+    /// A cloneable handle to the worker, e.g. to flush it from elsewhere in the application.
+    pub fn handle(&self) -> WorkerHandle {
+        WorkerHandle {
+            sender: self.sender.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+
+    /// Delivery statistics of the layer and the worker, e.g. to expose in a health check.
+    pub fn stats(&self) -> WorkerStats {
+        self.stats.clone()
+    }
+
+    /// Queue the payloads of the dead-letter spool to be sent again, e.g. on startup after a network
+    /// partition, and empty the spool.
+    ///
+    /// Payloads exhausting their retries again are written back to the spool. Returns the number of
+    /// events queued, which is zero if no spool is configured.
+    pub fn replay_dead_letters(&self) -> std::io::Result<usize> {
+        let spool = match &self.dead_letter {
+            Some(spool) => spool,
+            None => return Ok(0),
+        };
+        let mut events = 0;
+        let mut payloads = spool.take()?.into_iter();
+        while let Some(payload) = payloads.next() {
+            let count = payload.events();
+            if self.sender.force_send(WorkerMessage::Data(payload.clone())).is_err() {
+                // The worker has stopped, keep the remaining payloads in the spool.
+                for payload in std::iter::once(payload).chain(payloads) {
+                    spool.append(&payload)?;
+                }
+                return Err(std::io::Error::other("background worker has stopped"));
+            }
+            self.stats.counters.record_enqueued(count);
+            events += count;
+        }
+        Ok(events)
+    }
+
+    /// Initiate the worker's shutdown sequence, giving up on the remaining payloads once the timeout
+    /// elapses.
+    ///
+    /// The payloads queued before shutting down, and the repeats of aggregated events counted so far,
+    /// are sent until the deadline, including any retries.
+    /// Returns how many events were delivered, dropped, or still pending when the worker stopped.
+    pub async fn shutdown_with_timeout(self, timeout: Duration) -> ShutdownReport {
+        // The worker may already have stopped, in which case there is no deadline to enforce.
+        let _ = self.deadline.send(Some(Instant::now() + timeout));
+        self.stop().await
+    }
+
+    async fn stop(self) -> ShutdownReport {
+        let _ = self.sender.send(WorkerMessage::Shutdown);
+        let mut report = match self.join_handle.await {
+            Ok(report) => report,
+            Err(e) => {
+                tracing::error!(err = %e, "discord background worker failed");
+                ShutdownReport::default()
+            }
+        };
+        // Payloads queued after the worker stopped were never received by it.
+        report.pending += self.sender.pending_events();
+        report
+    }
+}
+
+/// A cloneable handle to the background worker, obtained from `BackgroundWorker::handle`.
+#[derive(Clone)]
+pub struct WorkerHandle {
+    sender: ChannelSender,
+    stats: WorkerStats,
+}
+
+impl WorkerHandle {
+    /// Wait until every payload queued before this call has been delivered, or has permanently
+    /// failed, without shutting down the worker.
+    ///
+    /// The repeats of aggregated events counted so far are reported too, without waiting for their
+    /// window to elapse.
+    ///
+    /// Resolves immediately if the worker has already stopped.
+    pub async fn flush(&self) {
+        flush(&self.sender).await
+    }
+
+    /// Delivery statistics of the layer and the worker, e.g. to expose in a health check.
+    pub fn stats(&self) -> WorkerStats {
+        self.stats.clone()
+    }
+}
+
+async fn flush(sender: &ChannelSender) {
+    let (ack, flushed) = oneshot::channel();
+    if sender.send(WorkerMessage::Flush(ack)).is_ok() {
+        // The worker stopped before processing the flush if the acknowledgement was dropped.
+        let _ = flushed.await;
+    }
+}
+
+/// Queue a message regardless of the queue's capacity, and block the current thread until it has
+/// been processed, or until the timeout elapses.
+///
+/// The worker must be able to make progress on another thread of the runtime for this to return
+/// before the timeout.
+pub(crate) fn send_blocking(sender: &ChannelSender, message: WorkerMessage, timeout: Duration) {
+    // Queueing from a thread outside of the runtime, which the current thread may belong to,
+    // ensures the worker is woken up on another thread of the runtime rather than on this one.
+    let sender = sender.clone();
+    let (done, wait) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let (ack, flushed) = oneshot::channel();
+        if sender.force_send(message).is_ok() && sender.send(WorkerMessage::Flush(ack)).is_ok() {
+            let _ = flushed.blocking_recv();
+        }
+        let _ = done.send(());
+    });
+    let _ = wait.recv_timeout(timeout);
+}
+
+/// What happened to the events sent by the background worker, as reported once it stops.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Number of events delivered to Discord.
+    pub delivered: usize,
+    /// Number of events that could not be delivered.
+    pub dropped: usize,
+    /// Number of events still waiting to be sent when the worker stopped.
+    pub pending: usize,
 }
 
 #[derive(Debug)]
 pub(crate) enum WorkerMessage {
     Data(MessagePayload),
+    Repeats(Repeats),
+    /// Acknowledged once every message queued before it has been processed.
+    Flush(oneshot::Sender<()>),
+    /// Forget the forum post with the given key, once the span it was opened for closed.
+    CloseThread(Fingerprint),
+    /// Forget the message posted for an event, once a whole aggregation window elapsed without
+    /// repeats of it, so that its next occurrence is posted as a new message.
+    Forget(Fingerprint),
     Shutdown,
 }
+
+impl WorkerMessage {
+    /// Number of events carried by the message.
+    pub(crate) fn events(&self) -> usize {
+        match self {
+            WorkerMessage::Data(payload) => payload.events(),
+            WorkerMessage::Repeats(_) => 1,
+            WorkerMessage::Flush(_)
+            | WorkerMessage::CloseThread(_)
+            | WorkerMessage::Forget(_)
+            | WorkerMessage::Shutdown => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::aggregate::{Occurrence, Summary};
+    use crate::queue;
+    use crate::testing::{contents, payload, TempFile};
+
+    /// A webhook answering requests after the given delay, on a thread of its own.
+    struct Webhook {
+        url: String,
+        requests: Arc<AtomicUsize>,
+    }
+
+    impl Webhook {
+        /// Answer every request with the created message.
+        fn start(delay: Duration) -> Self {
+            Self::responding(&[200], delay)
+        }
+
+        /// Answer requests with the given statuses in order, and with the last one once they ran out.
+        fn responding(statuses: &[u16], delay: Duration) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}/api/webhooks/1/token", listener.local_addr().unwrap());
+            let requests = Arc::new(AtomicUsize::new(0));
+            let counted = requests.clone();
+            let statuses: Arc<[u16]> = statuses.into();
+            std::thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let (counted, statuses) = (counted.clone(), statuses.clone());
+                    std::thread::spawn(move || Self::serve(stream, delay, &counted, &statuses));
+                }
+            });
+            Self { url, requests }
+        }
+
+        fn requests(&self) -> usize {
+            self.requests.load(Ordering::SeqCst)
+        }
+
+        fn serve(stream: TcpStream, delay: Duration, requests: &AtomicUsize, statuses: &[u16]) {
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            loop {
+                let mut content_length = 0;
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                        return;
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                    let lower = line.to_ascii_lowercase();
+                    if let Some(length) = lower.strip_prefix("content-length:") {
+                        content_length = length.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                if reader.read_exact(&mut body).is_err() {
+                    return;
+                }
+                std::thread::sleep(delay);
+                let request = requests.fetch_add(1, Ordering::SeqCst);
+                let status = statuses[request.min(statuses.len() - 1)];
+                let body = match status {
+                    204 => "",
+                    200..=299 => r#"{"id": "1", "channel_id": "2"}"#,
+                    429 => r#"{"message": "You are being rate limited.", "retry_after": 0.05, "global": false}"#,
+                    _ => r#"{"message": "error"}"#,
+                };
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                if writer.write_all(response.as_bytes()).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn spawn_worker(aggregator: Option<Arc<Aggregator>>) -> (ChannelSender, JoinHandle<ShutdownReport>) {
+        let counters = Arc::new(Counters::default());
+        let (sender, receiver) = queue::channel(None, OverflowPolicy::default(), counters.clone(), None);
+        // The deadline is never set, as its sender is dropped right away.
+        let (_, deadline) = watch::channel(None);
+        let worker = worker(receiver, WorkerConfig::default(), aggregator, deadline, counters);
+        (sender, tokio::spawn(worker))
+    }
+
+    /// Record an occurrence of the same event, which must not open a new window.
+    fn repeat(aggregator: &Aggregator, webhook_url: &str) {
+        let summary = || Summary {
+            app_name: "app".to_string(),
+            target: "server".to_string(),
+            message: "boom".to_string(),
+            webhook_url: webhook_url.to_string(),
+            thread: Thread::Channel,
+        };
+        if let Occurrence::First(Some(_)) = aggregator.record(Fingerprint::of(&"boom"), summary) {
+            panic!("the window of the event elapsed");
+        }
+    }
+
+    /// Record a repeat of the same event every millisecond, until aborted.
+    fn repeat_forever(aggregator: Arc<Aggregator>, webhook_url: String) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                repeat(&aggregator, &webhook_url);
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn flush_completes_while_events_keep_repeating() {
+        let webhook = Webhook::start(Duration::from_millis(50));
+        let aggregator = Arc::new(Aggregator::new(Duration::from_secs(60), false));
+        let (sender, worker) = spawn_worker(Some(aggregator.clone()));
+        // Open the window, so that every later occurrence is a repeat.
+        repeat(&aggregator, &webhook.url);
+        let repeating = repeat_forever(aggregator.clone(), webhook.url.clone());
+        for _ in 0..3 {
+            // Whether or not the repeating task got to run, there are repeats for the flush to report.
+            repeat(&aggregator, &webhook.url);
+            tokio::time::timeout(Duration::from_secs(5), flush(&sender))
+                .await
+                .expect("flush did not complete while the event kept repeating");
+        }
+        // Each flush reported the repeats counted before it, once.
+        assert_eq!(webhook.requests(), 3);
+        let _ = sender.send(WorkerMessage::Shutdown);
+        tokio::time::timeout(Duration::from_secs(5), worker)
+            .await
+            .expect("shutdown did not complete while the event kept repeating")
+            .unwrap();
+        repeating.abort();
+    }
+
+    fn status(code: u16) -> DeliveryError {
+        DeliveryError::Status {
+            status: StatusCode::from_u16(code).unwrap(),
+            body: String::new(),
+        }
+    }
+
+    /// Post a text message to the webhook, with a worker of its own.
+    async fn post(webhook: &Webhook) -> Result<(), DeliveryError> {
+        let (_, deadline) = watch::channel(None);
+        let mut worker = Worker::new(deadline, Arc::default(), Identity::default());
+        let payload = MessagePayload::new(
+            crate::PayloadMessageType::TextNoEmbed("boom".to_string()),
+            webhook.url.clone(),
+        );
+        worker.post(payload).await
+    }
+
+    #[test]
+    fn client_errors_are_permanent() {
+        for code in [400, 401, 403, 404, 413] {
+            assert!(!status(code).is_retryable(), "{}", code);
+        }
+        assert!(status(404).is_not_found());
+        assert!(!DeliveryError::InvalidUrl("nope".to_string()).is_retryable());
+        assert!(!DeliveryError::RetriesExhausted(Box::new(status(500))).is_retryable());
+        assert!(!DeliveryError::DeadlineElapsed.is_retryable());
+    }
+
+    #[test]
+    fn timeouts_rate_limits_and_server_errors_are_retryable() {
+        for code in [408, 429, 500, 502, 503, 504] {
+            assert!(status(code).is_retryable(), "{}", code);
+        }
+        assert!(status(429).is_rate_limited());
+        assert!(!status(503).is_rate_limited());
+    }
+
+    #[tokio::test]
+    async fn transport_errors_are_retryable() {
+        // Nothing listens on the port of a listener which was dropped.
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let error = reqwest::get(format!("http://{}", address)).await.unwrap_err();
+        assert!(DeliveryError::Transport(error).is_retryable());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn successful_requests_are_sent_once() {
+        for code in [200, 204] {
+            let webhook = Webhook::responding(&[code], Duration::ZERO);
+            assert!(post(&webhook).await.is_ok(), "{}", code);
+            assert_eq!(webhook.requests(), 1);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn permanent_failures_are_not_retried() {
+        for code in [400, 401, 404] {
+            let webhook = Webhook::responding(&[code, 200], Duration::ZERO);
+            match post(&webhook).await {
+                Err(DeliveryError::Status { status, .. }) => assert_eq!(status.as_u16(), code),
+                other => panic!("expected a {} status, got {:?}", code, other),
+            }
+            assert_eq!(webhook.requests(), 1);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn transient_failures_are_retried() {
+        for code in [408, 429, 500, 503] {
+            let webhook = Webhook::responding(&[code, code, 200], Duration::ZERO);
+            assert!(post(&webhook).await.is_ok(), "{}", code);
+            assert_eq!(webhook.requests(), 3);
+        }
+    }
+
+    /// A worker replaying the given spool into a queue, which is returned instead of being consumed.
+    fn replaying(spool: &DeadLetterSpool) -> (BackgroundWorker, ChannelReceiver) {
+        let counters = Arc::new(Counters::default());
+        let (sender, receiver) = queue::channel(None, OverflowPolicy::default(), counters.clone(), None);
+        let stats = WorkerStats {
+            counters,
+            queue: sender.depth(),
+        };
+        let worker = BackgroundWorker {
+            sender,
+            join_handle: tokio::spawn(async { ShutdownReport::default() }),
+            deadline: watch::channel(None).0,
+            stats,
+            dead_letter: Some(spool.clone()),
+        };
+        (worker, receiver)
+    }
+
+    #[tokio::test]
+    async fn replay_queues_the_dead_letters() {
+        let file = TempFile::new("dead-letter");
+        let spool = DeadLetterSpool::new(file.path().to_path_buf());
+        spool.append(&payload("first")).unwrap();
+        spool.append(&payload("second")).unwrap();
+        let (worker, mut receiver) = replaying(&spool);
+
+        assert_eq!(worker.replay_dead_letters().unwrap(), 2);
+        let mut replayed = Vec::new();
+        for _ in 0..2 {
+            match receiver.recv().await {
+                Some(WorkerMessage::Data(payload)) => replayed.push(payload),
+                other => panic!("expected a payload, got {:?}", other),
+            }
+        }
+        assert_eq!(contents(&replayed), ["first", "second"]);
+        assert!(spool.take().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn replay_keeps_the_dead_letters_once_the_worker_stopped() {
+        let file = TempFile::new("dead-letter");
+        let spool = DeadLetterSpool::new(file.path().to_path_buf());
+        spool.append(&payload("first")).unwrap();
+        spool.append(&payload("second")).unwrap();
+        let (worker, receiver) = replaying(&spool);
+        drop(receiver);
+
+        assert!(worker.replay_dead_letters().is_err());
+        assert_eq!(contents(&spool.take().unwrap()), ["first", "second"]);
+    }
+
+    /// A background worker sending the payloads it is given, with a deadline set once shutting down.
+    fn background_worker() -> BackgroundWorker {
+        let counters = Arc::new(Counters::default());
+        let (sender, receiver) = queue::channel(None, OverflowPolicy::default(), counters.clone(), None);
+        let stats = WorkerStats {
+            counters: counters.clone(),
+            queue: sender.depth(),
+        };
+        let (deadline, deadline_rx) = watch::channel(None);
+        let worker = worker(receiver, WorkerConfig::default(), None, deadline_rx, counters);
+        BackgroundWorker {
+            sender,
+            join_handle: tokio::spawn(worker),
+            deadline,
+            stats,
+            dead_letter: None,
+        }
+    }
+
+    fn payload_to(webhook: &Webhook, text: &str) -> WorkerMessage {
+        WorkerMessage::Data(MessagePayload::new(
+            crate::PayloadMessageType::TextNoEmbed(text.to_string()),
+            webhook.url.clone(),
+        ))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn shutdown_gives_up_on_retries_and_queued_payloads_at_the_deadline() {
+        // The first payload is delivered, the next one rejected, and the one after it retried until
+        // the deadline elapses.
+        let webhook = Webhook::responding(&[200, 404, 503], Duration::ZERO);
+        let worker = background_worker();
+        worker.sender.send(payload_to(&webhook, "delivered")).unwrap();
+        worker.flush().await;
+        for text in ["rejected", "retried", "queued", "queued"] {
+            worker.sender.send(payload_to(&webhook, text)).unwrap();
+        }
+
+        let started = Instant::now();
+        let report = tokio::time::timeout(
+            Duration::from_secs(5),
+            worker.shutdown_with_timeout(Duration::from_millis(500)),
+        )
+        .await
+        .expect("shutdown did not complete by its deadline");
+        assert!(started.elapsed() >= Duration::from_millis(500));
+        assert!(started.elapsed() < Duration::from_secs(2), "{:?}", started.elapsed());
+        assert_eq!(
+            report,
+            ShutdownReport {
+                delivered: 1,
+                dropped: 1,
+                pending: 3,
+            }
+        );
+        assert!(webhook.requests() >= 3);
+    }
+}